@@ -0,0 +1,152 @@
+use clap::ValueEnum;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+
+/// Compression format of an input or output stream
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Compression {
+    /// Uncompressed
+    None,
+    Gzip,
+    Zstd,
+    Xz,
+    Bzip2,
+    /// LZ4 frames; fast, which suits temporary files
+    Lz4,
+}
+
+/// Compression formats fast enough for temporary files, which are written and read once
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum TempCompression {
+    /// Uncompressed
+    None,
+    Lz4,
+    Zstd,
+}
+
+impl From<TempCompression> for Compression {
+    fn from(compression: TempCompression) -> Self {
+        match compression {
+            TempCompression::None => Compression::None,
+            TempCompression::Lz4 => Compression::Lz4,
+            TempCompression::Zstd => Compression::Zstd,
+        }
+    }
+}
+
+/// Bytes at the start of a stream that `Compression::detect` needs to recognize any format
+pub const MAGIC_LEN: usize = 10;
+
+impl Compression {
+    /// Identifies the compression format from the first bytes of a stream
+    pub fn detect(magic: &[u8]) -> Self {
+        if magic.starts_with(&[0x1f, 0x8b]) {
+            Compression::Gzip
+        } else if magic.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]) {
+            Compression::Zstd
+        } else if magic.starts_with(&[0xfd, b'7', b'z', b'X', b'Z', 0x00]) {
+            Compression::Xz
+        } else if is_bzip2(magic) {
+            Compression::Bzip2
+        } else if magic.starts_with(&[0x04, 0x22, 0x4d, 0x18]) {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+
+    /// Picks the compression format matching a file name extension
+    pub fn from_extension(path: &Path) -> Self {
+        match path.extension().and_then(|extension| extension.to_str()) {
+            Some("gz" | "gzip") => Compression::Gzip,
+            Some("zst" | "zstd") => Compression::Zstd,
+            Some("xz") => Compression::Xz,
+            Some("bz2") => Compression::Bzip2,
+            Some("lz4") => Compression::Lz4,
+            _ => Compression::None,
+        }
+    }
+
+    /// Wraps a reader with a decoder for this format
+    ///
+    /// Concatenated streams, as written by `cat a.gz b.gz` or parallel compressors, are
+    /// decoded as one.
+    pub fn decoder(self, reader: Box<dyn BufRead>) -> io::Result<Box<dyn BufRead>> {
+        Ok(match self {
+            Compression::None => reader,
+            Compression::Gzip => Box::new(BufReader::new(flate2::bufread::MultiGzDecoder::new(reader))),
+            Compression::Zstd => Box::new(BufReader::new(zstd::stream::read::Decoder::with_buffer(reader)?)),
+            Compression::Xz => Box::new(BufReader::new(xz2::bufread::XzDecoder::new_multi_decoder(reader))),
+            Compression::Bzip2 => Box::new(BufReader::new(bzip2::bufread::MultiBzDecoder::new(reader))),
+            Compression::Lz4 => Box::new(BufReader::new(lz4_flex::frame::FrameDecoder::new(reader))),
+        })
+    }
+
+    /// Wraps a writer with an encoder for this format
+    pub fn encoder<W: Write>(self, writer: W) -> io::Result<Encoder<W>> {
+        Ok(match self {
+            Compression::None => Encoder::None(writer),
+            Compression::Gzip => Encoder::Gzip(flate2::write::GzEncoder::new(writer, flate2::Compression::default())),
+            Compression::Zstd => Encoder::Zstd(zstd::stream::write::Encoder::new(writer, 0)?),
+            Compression::Xz => Encoder::Xz(xz2::write::XzEncoder::new(writer, 6)),
+            Compression::Bzip2 => Encoder::Bzip2(bzip2::write::BzEncoder::new(writer, bzip2::Compression::default())),
+            Compression::Lz4 => Encoder::Lz4(lz4_flex::frame::FrameEncoder::new(writer)),
+        })
+    }
+}
+
+/// Whether a stream starts with a bzip2 header: `BZh`, the block size digit and the
+/// first block's magic number, which plain text is very unlikely to contain
+fn is_bzip2(magic: &[u8]) -> bool {
+    magic.len() >= 10
+        && magic.starts_with(b"BZh")
+        && (b'1'..=b'9').contains(&magic[3])
+        && magic[4..10] == [0x31, 0x41, 0x59, 0x26, 0x53, 0x59]
+}
+
+/// A writer that compresses what is written to it
+///
+/// Call `finish` once writing is complete; dropping an encoder may leave its stream truncated.
+pub enum Encoder<W: Write> {
+    None(W),
+    Gzip(flate2::write::GzEncoder<W>),
+    Zstd(zstd::stream::write::Encoder<'static, W>),
+    Xz(xz2::write::XzEncoder<W>),
+    Bzip2(bzip2::write::BzEncoder<W>),
+    Lz4(lz4_flex::frame::FrameEncoder<W>),
+}
+
+impl<W: Write> Encoder<W> {
+    /// Writes the end of the compressed stream and returns the underlying writer
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Encoder::None(writer) => Ok(writer),
+            Encoder::Gzip(encoder) => encoder.finish(),
+            Encoder::Zstd(encoder) => encoder.finish(),
+            Encoder::Xz(encoder) => encoder.finish(),
+            Encoder::Bzip2(encoder) => encoder.finish(),
+            Encoder::Lz4(encoder) => encoder.finish().map_err(io::Error::from),
+        }
+    }
+
+    fn inner(&mut self) -> &mut dyn Write {
+        match self {
+            Encoder::None(writer) => writer,
+            Encoder::Gzip(encoder) => encoder,
+            Encoder::Zstd(encoder) => encoder,
+            Encoder::Xz(encoder) => encoder,
+            Encoder::Bzip2(encoder) => encoder,
+            Encoder::Lz4(encoder) => encoder,
+        }
+    }
+}
+
+impl<W: Write> Write for Encoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner().flush()
+    }
+}
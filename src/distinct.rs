@@ -0,0 +1,89 @@
+use crate::run::Line;
+use crate::Keep;
+use hashbrown::HashTable;
+use std::hash::{BuildHasher, RandomState};
+use std::mem::size_of;
+
+/// Records deduplicated in memory with a hash table, for inputs whose distinct records fit
+/// in the memory budget
+///
+/// One entry is kept per key, or per key and input when inputs are kept apart, holding the
+/// surviving occurrence and the count of its duplicates, which is what deduplicating a chunk
+/// produces. Entries live in a vector
+/// that the table indexes, so that they can become a chunk without being copied.
+pub struct DistinctLines {
+    lines: Vec<Line>,
+    /// Positions in `lines`, hashed by key and, when `per_source`, input
+    index: HashTable<usize>,
+    hasher: RandomState,
+    keep: Keep,
+    per_source: bool,
+    bytes: usize,
+}
+
+impl DistinctLines {
+    pub fn new(keep: Keep, per_source: bool) -> Self {
+        DistinctLines {
+            lines: Vec::new(),
+            index: HashTable::new(),
+            hasher: RandomState::new(),
+            keep,
+            per_source,
+            bytes: 0,
+        }
+    }
+
+    /// Adds a line, folding it into the entry of an earlier duplicate, from the same input
+    /// when inputs are kept apart
+    ///
+    /// Lines must be added in input order.
+    pub fn insert(&mut self, line: Line) {
+        let (hasher, per_source) = (&self.hasher, self.per_source);
+        let hash = hash_entry(hasher, &line, per_source);
+        let lines = &mut self.lines;
+        let found = self.index.find(hash, |&i| same_entry(&lines[i], &line, per_source));
+        match found {
+            Some(&i) => {
+                let previous = &mut lines[i];
+                let previous_bytes = previous.memory_size();
+                previous.absorb(line, self.keep);
+                self.bytes = self.bytes - previous_bytes + previous.memory_size();
+            }
+            None => {
+                self.bytes += line.memory_size();
+                lines.push(line);
+                self.index.insert_unique(hash, lines.len() - 1, |&i| hash_entry(hasher, &lines[i], per_source));
+            }
+        }
+    }
+
+    /// Approximate memory held by the entries and the table, including the allocation the
+    /// next insertion would make while the old one is still held
+    pub fn memory_size(&self) -> usize {
+        // Each bucket of the table holds an index and a control byte
+        let bucket = size_of::<usize>() + 1;
+        let mut size = self.bytes
+            + (self.lines.capacity() - self.lines.len()) * size_of::<Line>()
+            + self.index.capacity() * bucket;
+        if self.lines.len() == self.lines.capacity() {
+            size += (self.lines.capacity() * 2).max(4) * size_of::<Line>();
+        }
+        if self.index.len() == self.index.capacity() {
+            size += (self.index.capacity() * 2).max(4) * bucket;
+        }
+        size
+    }
+
+    /// Returns the entries in the order their keys first appeared
+    pub fn into_lines(self) -> Vec<Line> {
+        self.lines
+    }
+}
+
+fn hash_entry(hasher: &RandomState, line: &Line, per_source: bool) -> u64 {
+    hasher.hash_one((line.key(), per_source.then_some(line.source)))
+}
+
+fn same_entry(a: &Line, b: &Line, per_source: bool) -> bool {
+    a.key() == b.key() && (!per_source || a.source == b.source)
+}
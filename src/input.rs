@@ -0,0 +1,157 @@
+use crate::compression::{Compression, MAGIC_LEN};
+use encoding_rs::Encoding;
+use encoding_rs_io::DecodeReaderBytesBuilder;
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, Read};
+use std::path::{Path, PathBuf};
+
+/// Path that stands for standard input
+const STDIN: &str = "-";
+
+/// Expands an `--input` argument into the files it names
+///
+/// The argument may be a file, a directory whose files are read recursively, or a glob
+/// pattern such as `logs/*.log`. Files are returned in a stable, sorted order.
+pub fn expand_input(input: &str) -> io::Result<Vec<PathBuf>> {
+    let path = Path::new(input);
+    if input == STDIN {
+        return Ok(vec![path.to_path_buf()]);
+    }
+    if path.is_dir() {
+        let mut files = Vec::new();
+        collect_files(path, &mut files)?;
+        return Ok(files);
+    }
+    if path.exists() || !is_glob_pattern(input) {
+        // Missing plain paths are reported when the file is opened
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let entries = glob::glob(input).map_err(|e| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("invalid glob pattern '{}': {}", input, e))
+    })?;
+    let mut files = Vec::new();
+    for entry in entries {
+        let path = entry.map_err(io::Error::from)?;
+        if path.is_dir() {
+            collect_files(&path, &mut files)?;
+        } else {
+            files.push(path);
+        }
+    }
+    if files.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no files match '{}'", input),
+        ));
+    }
+    Ok(files)
+}
+
+/// Whether an argument contains glob metacharacters
+fn is_glob_pattern(input: &str) -> bool {
+    input.contains(['*', '?', '['])
+}
+
+/// Appends every file below `dir` to `files`, descending into subdirectories in name order
+///
+/// Symbolic links to directories are not followed, so link cycles cannot recurse forever.
+fn collect_files(dir: &Path, files: &mut Vec<PathBuf>) -> io::Result<()> {
+    let mut entries = fs::read_dir(dir)?.collect::<io::Result<Vec<_>>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type()?.is_dir() {
+            collect_files(&path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Opens an input file for reading, or standard input for `-`
+///
+/// Compressed input is recognized by its magic bytes and decompressed transparently. With
+/// an `encoding`, the text is then decoded from it into UTF-8, dropping any byte order mark.
+pub fn open_input(path: &Path, encoding: Option<&'static Encoding>) -> io::Result<Box<dyn BufRead>> {
+    let reader: Box<dyn BufRead> = if path == Path::new(STDIN) {
+        Box::new(io::stdin().lock())
+    } else {
+        Box::new(BufReader::new(File::open(path)?))
+    };
+    let reader = decompress(reader)?;
+    Ok(match encoding {
+        Some(encoding) => Box::new(BufReader::new(
+            DecodeReaderBytesBuilder::new().encoding(Some(encoding)).strip_bom(true).build(reader),
+        )),
+        None => reader,
+    })
+}
+
+/// Decompresses a stream in whichever format its magic bytes name, if any
+fn decompress(mut reader: Box<dyn BufRead>) -> io::Result<Box<dyn BufRead>> {
+    // A single read from a pipe may return fewer bytes than the magic, so read until there
+    // are enough or the stream ends, then put them back in front of the rest
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    (&mut reader).take(MAGIC_LEN as u64).read_to_end(&mut magic)?;
+    let compression = Compression::detect(&magic);
+    compression.decoder(Box::new(io::Cursor::new(magic).chain(reader)))
+}
+
+/// Whether an input is a regular file that can be read more than once
+///
+/// Standard input, pipes and other special files are consumed by reading them.
+pub fn is_seekable(path: &Path) -> bool {
+    path != Path::new(STDIN) && fs::metadata(path).is_ok_and(|metadata| metadata.is_file())
+}
+
+/// Typical expansion of compressed text, used to estimate the decompressed size of an input
+const COMPRESSION_RATIO: u64 = 5;
+
+/// Estimates how many bytes an input holds once decompressed, or `None` when it cannot be
+/// known without consuming it
+pub fn estimated_size(path: &Path) -> Option<u64> {
+    if !is_seekable(path) {
+        return None;
+    }
+    let mut file = File::open(path).ok()?;
+    let size = file.metadata().ok()?.len();
+    let mut magic = Vec::with_capacity(MAGIC_LEN);
+    file.by_ref().take(MAGIC_LEN as u64).read_to_end(&mut magic).ok()?;
+    match Compression::detect(&magic) {
+        Compression::None => Some(size),
+        _ => Some(size * COMPRESSION_RATIO),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Reads `bytes` back through `decompress`, one byte per read like a slow pipe
+    fn read_trickled(bytes: Vec<u8>) -> Vec<u8> {
+        let reader = Box::new(BufReader::with_capacity(1, io::Cursor::new(bytes)));
+        let mut text = Vec::new();
+        decompress(reader).unwrap().read_to_end(&mut text).unwrap();
+        text
+    }
+
+    #[test]
+    fn detects_magic_split_across_reads() {
+        let text = b"b\na\nb\n";
+        for compression in [Compression::Gzip, Compression::Zstd, Compression::Xz, Compression::Bzip2, Compression::Lz4] {
+            let mut encoder = compression.encoder(Vec::new()).unwrap();
+            encoder.write_all(text).unwrap();
+            assert_eq!(read_trickled(encoder.finish().unwrap()), text);
+        }
+    }
+
+    #[test]
+    fn passes_through_short_plain_input() {
+        assert_eq!(read_trickled(Vec::new()), b"");
+        assert_eq!(read_trickled(b"BZh".to_vec()), b"BZh");
+        assert_eq!(read_trickled(b"plain text\n".to_vec()), b"plain text\n");
+    }
+}
@@ -0,0 +1,294 @@
+use crate::record::Format;
+use clap::ValueEnum;
+use serde_json::{Number, Value};
+use std::io;
+use unicode_normalization::UnicodeNormalization;
+
+/// Extracts the key that lines are sorted and compared by
+pub enum KeyExtractor {
+    /// The whole line is the key
+    WholeLine,
+    /// The key is made of the given zero-based fields of a delimited line
+    Fields { fields: Vec<usize>, delimiter: char },
+    /// The key is made of the unquoted values of a CSV record, either the given
+    /// zero-based fields or all of them
+    CsvFields { fields: Option<Vec<usize>>, delimiter: char },
+    /// The key is the canonical form of a JSON value, either the whole value or the
+    /// values at the given JSON pointers
+    Json { pointers: Option<Vec<String>> },
+}
+
+impl KeyExtractor {
+    /// Builds an extractor from the `--key` values given on the command line
+    ///
+    /// Keys are one-based field numbers for line and CSV input, and JSON pointers such as
+    /// `/user/id` for JSON Lines input.
+    pub fn new(format: Format, keys: &[String], delimiter: char) -> Result<Self, String> {
+        if format == Format::Jsonl {
+            if let Some(pointer) = keys.iter().find(|pointer| !pointer.is_empty() && !pointer.starts_with('/')) {
+                return Err(format!("invalid JSON pointer '{}': must be empty or start with '/'", pointer));
+            }
+            let pointers = (!keys.is_empty()).then(|| keys.to_vec());
+            return Ok(KeyExtractor::Json { pointers });
+        }
+
+        let fields = keys
+            .iter()
+            .map(|key| parse_field(key).map(|field| field - 1))
+            .collect::<Result<Vec<_>, _>>()?;
+        let fields = (!fields.is_empty()).then_some(fields);
+        Ok(match (format, fields) {
+            (Format::Lines, None) => KeyExtractor::WholeLine,
+            (Format::Lines, Some(fields)) => KeyExtractor::Fields { fields, delimiter },
+            (_, fields) => KeyExtractor::CsvFields { fields, delimiter },
+        })
+    }
+
+    /// Returns the key for `line`, or `None` when the key is the line itself
+    ///
+    /// Delimited fields are split byte-wise; CSV and JSON records must be valid UTF-8.
+    pub fn extract(&self, line: &[u8]) -> io::Result<Option<Vec<u8>>> {
+        Ok(match self {
+            KeyExtractor::WholeLine => None,
+            KeyExtractor::Fields { fields, delimiter } => {
+                let columns = split_fields(line, delimiter.encode_utf8(&mut [0; 4]).as_bytes());
+                Some(join_fields(fields, &columns))
+            }
+            KeyExtractor::CsvFields { fields, delimiter } => {
+                let line = std::str::from_utf8(line)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid UTF-8: {}", e)))?;
+                let columns = parse_csv_fields(line, *delimiter);
+                Some(match fields {
+                    Some(fields) => join_fields(fields, &columns),
+                    None => columns.join("\0").into_bytes(),
+                })
+            }
+            KeyExtractor::Json { pointers } => {
+                let value: Value = serde_json::from_slice(line)
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("invalid JSON: {}", e)))?;
+                Some(match pointers {
+                    Some(pointers) => {
+                        // A missing value gives an empty part, which no canonical JSON value produces
+                        let parts: Vec<String> = pointers
+                            .iter()
+                            .map(|pointer| value.pointer(pointer).map_or_else(String::new, canonical_json))
+                            .collect();
+                        parts.join("\0").into_bytes()
+                    }
+                    None => canonical_json(&value).into_bytes(),
+                })
+            }
+        })
+    }
+}
+
+/// Serializes a JSON value in canonical form: compact, with object keys sorted and
+/// integral numbers written without a fraction or exponent
+fn canonical_json(value: &Value) -> String {
+    // `serde_json` keeps object keys in a sorted map, so serializing sorts them
+    normalize_numbers(value.clone()).to_string()
+}
+
+/// Rewrites floats with integral values, such as `1.0` or `1e3`, as integers
+fn normalize_numbers(value: Value) -> Value {
+    match value {
+        Value::Number(number) => match number.as_f64() {
+            Some(float) if number.is_f64() && float.fract() == 0.0 && float.abs() < i64::MAX as f64 => {
+                Value::Number(Number::from(float as i64))
+            }
+            _ => Value::Number(number),
+        },
+        Value::Array(items) => Value::Array(items.into_iter().map(normalize_numbers).collect()),
+        Value::Object(map) => Value::Object(map.into_iter().map(|(k, v)| (k, normalize_numbers(v))).collect()),
+        other => other,
+    }
+}
+
+/// Joins the selected fields into a key
+///
+/// Missing fields count as empty; NUL joins the parts so that keys order field by field.
+fn join_fields<S: AsRef<[u8]>>(fields: &[usize], columns: &[S]) -> Vec<u8> {
+    let parts: Vec<&[u8]> = fields
+        .iter()
+        .map(|&field| columns.get(field).map_or(&[][..], |column| column.as_ref()))
+        .collect();
+    parts.join(&0)
+}
+
+/// Splits a record at each occurrence of `delimiter`
+fn split_fields<'a>(record: &'a [u8], delimiter: &[u8]) -> Vec<&'a [u8]> {
+    let mut fields = Vec::new();
+    let mut start = 0;
+    let mut index = 0;
+    while index + delimiter.len() <= record.len() {
+        if record[index..].starts_with(delimiter) {
+            fields.push(&record[start..index]);
+            index += delimiter.len();
+            start = index;
+        } else {
+            index += 1;
+        }
+    }
+    fields.push(&record[start..]);
+    fields
+}
+
+/// Splits an RFC 4180 record into its field values, removing quotes and unescaping `""`
+fn parse_csv_fields(record: &str, delimiter: char) -> Vec<String> {
+    let mut fields = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = record.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            if c == '"' {
+                if chars.peek() == Some(&'"') {
+                    field.push('"');
+                    chars.next();
+                } else {
+                    in_quotes = false;
+                }
+            } else {
+                field.push(c);
+            }
+        } else if c == '"' {
+            in_quotes = true;
+        } else if c == delimiter {
+            fields.push(std::mem::take(&mut field));
+        } else {
+            field.push(c);
+        }
+    }
+    fields.push(field);
+    fields
+}
+
+/// Parses a one-based field number for `--key`
+fn parse_field(value: &str) -> Result<usize, String> {
+    match value.trim().parse::<usize>() {
+        Ok(0) => Err("field numbers start at 1".to_string()),
+        Ok(field) => Ok(field),
+        Err(e) => Err(format!("invalid field number '{}': {}", value, e)),
+    }
+}
+
+/// Unicode normalization form applied to keys before comparison
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum UnicodeForm {
+    /// Canonical composition
+    Nfc,
+    /// Compatibility composition
+    Nfkc,
+}
+
+/// Transformations applied to extracted keys so that equivalent records compare equal
+///
+/// Only the key is transformed; the record written to the output keeps its original text.
+#[derive(Default)]
+pub struct Normalization {
+    /// Unicode normalization form
+    pub unicode: Option<UnicodeForm>,
+    /// Compare lowercased keys
+    pub ignore_case: bool,
+    /// Compare keys after full Unicode case folding, e.g. `ß` matches `ss`
+    pub casefold: bool,
+    /// Strip leading and trailing whitespace
+    pub trim: bool,
+    /// Collapse each run of whitespace into a single space
+    pub squeeze_whitespace: bool,
+    /// Treat CRLF and LF line endings, including those inside multi-line records, as equal
+    pub ignore_line_endings: bool,
+}
+
+impl Normalization {
+    /// Whether keys are changed at all, in which case whole-line keys are stored separately
+    pub fn transforms(&self) -> bool {
+        self.unicode.is_some()
+            || self.ignore_case
+            || self.casefold
+            || self.trim
+            || self.squeeze_whitespace
+            || self.ignore_line_endings
+    }
+
+    /// Normalizes the extracted key, or the whole line when the extractor returned `None`
+    ///
+    /// Normalization works on text, so any invalid UTF-8 in the key is replaced first.
+    pub fn apply(&self, key: Option<Vec<u8>>, line: &[u8]) -> Option<Vec<u8>> {
+        if !self.transforms() {
+            return key;
+        }
+        let key = key.unwrap_or_else(|| line.to_vec());
+        let mut key = String::from_utf8(key).unwrap_or_else(|e| String::from_utf8_lossy(e.as_bytes()).into_owned());
+        if self.ignore_line_endings {
+            key = key.replace("\r\n", "\n");
+            key.truncate(key.trim_end_matches('\r').len());
+        }
+        if self.trim {
+            key = key.trim().to_string();
+        }
+        if self.squeeze_whitespace {
+            key = squeeze_whitespace(&key);
+        }
+        if self.unicode.is_some() {
+            key = self.normalize_unicode(&key);
+        }
+        if self.casefold {
+            key = caseless::default_case_fold_str(&key);
+        } else if self.ignore_case {
+            key = key.to_lowercase();
+        }
+        // Changing case can leave the text unnormalized, so normalize once more
+        if self.unicode.is_some() && (self.casefold || self.ignore_case) {
+            key = self.normalize_unicode(&key);
+        }
+        Some(key.into_bytes())
+    }
+
+    fn normalize_unicode(&self, text: &str) -> String {
+        match self.unicode {
+            Some(UnicodeForm::Nfc) => text.nfc().collect(),
+            Some(UnicodeForm::Nfkc) => text.nfkc().collect(),
+            None => text.to_string(),
+        }
+    }
+}
+
+/// Replaces each run of whitespace with a single space
+fn squeeze_whitespace(text: &str) -> String {
+    let mut squeezed = String::with_capacity(text.len());
+    let mut in_whitespace = false;
+    for c in text.chars() {
+        if c.is_whitespace() {
+            if !in_whitespace {
+                squeezed.push(' ');
+            }
+            in_whitespace = true;
+        } else {
+            squeezed.push(c);
+            in_whitespace = false;
+        }
+    }
+    squeezed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn csv_fields() {
+        assert_eq!(parse_csv_fields("a,b,,c", ','), ["a", "b", "", "c"]);
+        assert_eq!(parse_csv_fields("\"a,b\",c", ','), ["a,b", "c"]);
+        assert_eq!(parse_csv_fields("\"say \"\"hi\"\"\",\"\"", ','), ["say \"hi\"", ""]);
+        assert_eq!(parse_csv_fields("\"one\ntwo\",x", ','), ["one\ntwo", "x"]);
+        assert_eq!(parse_csv_fields("\"one\r\ntwo\",x", ','), ["one\r\ntwo", "x"]);
+        assert_eq!(parse_csv_fields("a;\"b;c\"", ';'), ["a", "b;c"]);
+    }
+
+    #[test]
+    fn csv_unterminated_quote() {
+        assert_eq!(parse_csv_fields("a,\"open,b\n", ','), ["a", "open,b\n"]);
+    }
+}
@@ -1,10 +1,32 @@
-use clap::{Parser};
-use indicatif::{ProgressBar, ProgressStyle};
-use tempfile::NamedTempFile;
+mod compression;
+mod distinct;
+mod input;
+mod key;
+mod output;
+mod record;
+mod run;
+mod scratch;
+
+use clap::{Parser, ValueEnum};
+use encoding_rs::Encoding;
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
+use rayon::slice::ParallelSliceMut;
+use tempfile::{NamedTempFile, TempPath};
 use std::fs::File;
-use std::io::{BufRead, BufReader, Write};
+use std::io::{BufWriter, Write};
 use std::io;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc;
+use std::thread;
+
+use compression::{Compression, TempCompression};
+use distinct::DistinctLines;
+use key::{KeyExtractor, Normalization, UnicodeForm};
+use output::RecordWriter;
+use record::{Format, Grouping, RecordReader};
+use regex::bytes::Regex;
+use run::{write_run, Line, RunMerger, RunWriter, SortOrder};
+use scratch::ScratchDirs;
 
 /// CLI arguments
 #[derive(Parser)]
@@ -13,179 +35,1142 @@ use std::path::Path;
 #[command(version = "1.0")]
 #[command(about = "Removes duplicate lines from a file", long_about = None)]
 struct Cli {
-    /// Input file path
-    #[arg(short, long, value_name = "INPUT_FILE")]
-    input: String,
+    /// Input files, directories (read recursively) or glob patterns; `-` reads standard input
+    ///
+    /// With --mode other than union, each argument is one set, however many files it expands to.
+    #[arg(short, long, value_name = "INPUT", num_args = 1.., required = true)]
+    input: Vec<String>,
 
-    /// Output file path
+    /// Output file path; `-` writes to standard output
+    ///
+    /// Output is compressed when the name ends in .gz, .zst, .xz or .bz2.
     #[arg(short, long, value_name = "OUTPUT_FILE")]
     output: String,
+
+    /// Compress the output with this format regardless of the output file name
+    #[arg(long, value_enum, value_name = "FORMAT")]
+    compress: Option<Compression>,
+
+    /// Compress temporary files with this format to reduce scratch disk usage
+    #[arg(long, value_enum, value_name = "FORMAT", default_value_t = TempCompression::None)]
+    temp_compression: TempCompression,
+
+    /// Emit surviving lines in their original input order instead of sorted order
+    #[arg(long)]
+    keep_order: bool,
+
+    /// Which occurrence of each duplicated line to keep
+    #[arg(long, value_enum, default_value_t = Keep::First)]
+    keep: Keep,
+
+    /// Comma-separated field numbers (starting at 1), or JSON pointers with --format jsonl,
+    /// to deduplicate by instead of the whole record
+    #[arg(short, long, value_name = "KEYS", value_delimiter = ',')]
+    key: Vec<String>,
+
+    /// Field delimiter used with --key [default: tab, or ',' with --format csv]
+    #[arg(short, long)]
+    delimiter: Option<char>,
+
+    /// Layout of the records in the input
+    #[arg(short, long, value_enum, default_value_t = Format::Lines)]
+    format: Format,
+
+    /// Bytes that end each record in the input and output, with the escapes \0, \n, \r,
+    /// \t, \\ and \xHH [default: \n, also accepting \r\n in the input]
+    #[arg(long, value_name = "SEPARATOR", value_parser = parse_separator)]
+    // The full path stops clap from treating the bytes as a list of values
+    record_separator: Option<::std::vec::Vec<u8>>,
+
+    /// End records with NUL instead of newline, like `sort -z`
+    #[arg(short = 'z', long, conflicts_with = "record_separator")]
+    zero_terminated: bool,
+
+    /// Start a record at each line matching REGEX, grouping the lines up to the next match
+    /// into one record, e.g. '^\S' for stack traces
+    #[arg(long, value_name = "REGEX", value_parser = Regex::new, conflicts_with = "paragraph")]
+    record_start: Option<Regex>,
+
+    /// Treat paragraphs separated by blank lines as records, writing a blank line after each
+    #[arg(long)]
+    paragraph: bool,
+
+    /// Line ending for the output [default: that of the first input]
+    #[arg(long, value_enum, conflicts_with_all = ["record_separator", "zero_terminated"])]
+    line_ending: Option<LineEnding>,
+
+    /// Copy the first record to the top of the output unchanged instead of deduplicating it
+    #[arg(long)]
+    header: bool,
+
+    /// Character encoding of the input, e.g. latin1 or utf-16le, which is converted to
+    /// UTF-8 [default: records are read as raw bytes]
+    #[arg(long, value_name = "LABEL", value_parser = parse_encoding)]
+    encoding: Option<&'static Encoding>,
+
+    /// What to do with records that are not valid UTF-8 when the format or key
+    /// normalization needs text
+    #[arg(long, value_enum, value_name = "POLICY", default_value_t = InvalidUtf8::Error)]
+    on_invalid_utf8: InvalidUtf8,
+
+    /// Compare keys case-insensitively by lowercasing them
+    #[arg(long)]
+    ignore_case: bool,
+
+    /// Compare keys after full Unicode case folding (e.g. 'ß' matches "ss")
+    #[arg(long)]
+    casefold: bool,
+
+    /// Unicode normalization form applied to keys before comparison
+    #[arg(long, value_enum, value_name = "FORM")]
+    unicode_normalize: Option<UnicodeForm>,
+
+    /// Ignore leading and trailing whitespace when comparing keys
+    #[arg(long)]
+    trim: bool,
+
+    /// Treat any run of whitespace as a single space when comparing keys
+    #[arg(long)]
+    squeeze_whitespace: bool,
+
+    /// Treat CRLF and LF line endings as equal when comparing keys
+    #[arg(long)]
+    ignore_line_endings: bool,
+
+    /// Prefix each output record with the number of times it occurred
+    #[arg(short, long)]
+    count: bool,
+
+    /// Where --count writes the occurrence count
+    #[arg(long, value_enum, default_value_t = CountFormat::Prefix, requires = "count")]
+    count_format: CountFormat,
+
+    /// Only output records that occur more than once, like `uniq -d`
+    #[arg(short = 'D', long, conflicts_with = "only_unique")]
+    only_duplicates: bool,
+
+    /// Only output records that occur exactly once, like `uniq -u`
+    #[arg(short = 'u', long)]
+    only_unique: bool,
+
+    /// Only output records that occur at least N times
+    #[arg(long, value_name = "N")]
+    min_count: Option<u64>,
+
+    /// Only output records that occur at most N times
+    #[arg(long, value_name = "N")]
+    max_count: Option<u64>,
+
+    /// How records from multiple inputs are combined
+    #[arg(short, long, value_enum, default_value_t = SetMode::Union)]
+    mode: SetMode,
+
+    /// Memory to fill with records before sorting them into a temporary file, e.g. 512M or 4G
+    /// [default: half of the available memory]
+    #[arg(long, value_name = "SIZE", value_parser = parse_size)]
+    memory_limit: Option<usize>,
+
+    /// Directories for temporary files [default: the system temporary directory]
+    ///
+    /// Temporary files are spread across all given directories, e.g. one per disk.
+    #[arg(short = 'T', long, value_name = "DIR", num_args = 1..)]
+    temp_dir: Vec<PathBuf>,
+
+    /// Skip checking for enough free space for temporary files before starting
+    #[arg(long)]
+    no_space_check: bool,
+
+    /// Threads used to sort chunks [default: the number of CPUs]
+    ///
+    /// With more than one thread, the next chunk is read while the previous one is sorted
+    /// and written, so the memory limit is shared between two chunks.
+    #[arg(long, value_name = "N")]
+    threads: Option<usize>,
+
+    /// Most temporary files to merge at once [default: based on the open file limit]
+    ///
+    /// When there are more, they are merged in groups over several passes.
+    #[arg(long, value_name = "N", value_parser = clap::value_parser!(u32).range(2..))]
+    merge_fanin: Option<u32>,
 }
 
-const CHUNK_SIZE: usize = 50_000_000; // Lines per chunk (adjust based on available memory)
+/// Parses a byte size such as `4G`, `512M` or `1048576`, using binary multiples
+fn parse_size(value: &str) -> Result<usize, String> {
+    let value = value.trim();
+    let digits = value.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(value.len());
+    let (number, unit) = value.split_at(digits);
+    let number: f64 = number.parse().map_err(|_| format!("invalid size '{}'", value))?;
+    let multiplier: u64 = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        _ => return Err(format!("invalid size unit in '{}'; use K, M, G or T", value)),
+    };
+    Ok((number * multiplier as f64) as usize)
+}
 
-/// Processes a single chunk by deduplicating and writing it to a temporary file
+/// Parses a record separator, unescaping `\0`, `\n`, `\r`, `\t`, `\\` and `\xHH`
+fn parse_separator(value: &str) -> Result<Vec<u8>, String> {
+    let mut separator = Vec::new();
+    let mut bytes = value.bytes();
+    while let Some(byte) = bytes.next() {
+        if byte != b'\\' {
+            separator.push(byte);
+            continue;
+        }
+        separator.push(match bytes.next() {
+            Some(b'0') => 0,
+            Some(b'n') => b'\n',
+            Some(b'r') => b'\r',
+            Some(b't') => b'\t',
+            Some(b'\\') => b'\\',
+            Some(b'x') => {
+                let digits: Vec<u8> = bytes.by_ref().take(2).collect();
+                if digits.len() != 2 || !digits.iter().all(u8::is_ascii_hexdigit) {
+                    return Err(format!("invalid \\x escape in '{}'; use two hex digits", value));
+                }
+                u8::from_str_radix(std::str::from_utf8(&digits).expect("hex digits are ASCII"), 16)
+                    .expect("checked hex digits")
+            }
+            _ => return Err(format!("invalid escape in '{}'; use \\0, \\n, \\r, \\t, \\\\ or \\xHH", value)),
+        });
+    }
+    if separator.is_empty() {
+        return Err("the record separator cannot be empty".to_string());
+    }
+    Ok(separator)
+}
 
-fn remove_duplicates_large_file(input_path: &str, output_path: &str) -> std::io::Result<()> {
-    // Initialize a spinner to count lines
-    let progress_bar = ProgressBar::new_spinner();
+/// Looks up an encoding by one of its WHATWG labels, such as `latin1` or `shift_jis`
+fn parse_encoding(label: &str) -> Result<&'static Encoding, String> {
+    Encoding::for_label(label.trim().as_bytes()).ok_or_else(|| format!("unknown encoding '{}'", label))
+}
+
+/// Memory budget used when --memory-limit is not given: half of the memory currently
+/// available, leaving room for the page cache and the rest of the system
+fn default_memory_limit() -> usize {
+    const FALLBACK: u64 = 1 << 30;
+    let mut system = sysinfo::System::new();
+    system.refresh_memory();
+    let available = match system.available_memory() {
+        0 => FALLBACK * 2, // Not reported on this platform
+        available => available,
+    };
+    (available / 2) as usize
+}
+
+/// Merge fan-in used when --merge-fanin is not given: as many temporary files as the
+/// open file limit allows, keeping some descriptors for inputs, output and the runtime
+fn default_merge_fanin() -> usize {
+    const RESERVED_FILES: u64 = 32;
+    const MAX_FANIN: u64 = 1024;
+    #[cfg(unix)]
+    let limit = {
+        let mut limit = libc::rlimit { rlim_cur: 0, rlim_max: 0 };
+        // SAFETY: getrlimit only writes to the struct it is given
+        match unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut limit) } {
+            0 => limit.rlim_cur,
+            _ => 256,
+        }
+    };
+    // Windows handles are not limited in the same way
+    #[cfg(not(unix))]
+    let limit = MAX_FANIN + RESERVED_FILES;
+    limit.saturating_sub(RESERVED_FILES).clamp(2, MAX_FANIN) as usize
+}
+
+/// Which occurrence of a duplicated line survives deduplication
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum Keep {
+    /// Keep the first occurrence in the input
+    First,
+    /// Keep the last occurrence in the input
+    Last,
+}
+
+/// Handling of records that are not valid UTF-8 where text is required
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum InvalidUtf8 {
+    /// Leave the record out of the output
+    Skip,
+    /// Replace invalid sequences with U+FFFD, in the output too
+    Replace,
+    /// Stop with an error naming the record
+    Error,
+}
+
+/// Set operation applied across the inputs
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum SetMode {
+    /// Records found in any input
+    Union,
+    /// Records found in every input
+    Intersect,
+    /// Records of the first input not found in any other input
+    Subtract,
+    /// Records found in exactly one input
+    Symdiff,
+}
+
+/// Line ending written after each output record
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum LineEnding {
+    /// `\n`, as on Unix
+    Lf,
+    /// `\r\n`, as on Windows
+    Crlf,
+}
+
+/// How occurrence counts are written to the output
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum CountFormat {
+    /// `count<TAB>record`, like `uniq -c`
+    Prefix,
+    /// `record<DELIMITER>count`, appending the count as a final field
+    Column,
+}
+
+/// Options controlling how duplicates are detected, resolved and emitted
+struct DedupOptions {
+    format: Format,
+    compress: Option<Compression>,
+    temp_compression: Compression,
+    header: bool,
+    encoding: Option<&'static Encoding>,
+    on_invalid_utf8: InvalidUtf8,
+    keep_order: bool,
+    keep: Keep,
+    key: KeyExtractor,
+    normalization: Normalization,
+    count: Option<CountFormat>,
+    delimiter: char,
+    /// Bytes that end each line, in the input and the output
+    record_separator: Vec<u8>,
+    grouping: Grouping,
+    /// Line ending of the output, when not taken from the input
+    line_ending: Option<LineEnding>,
+    /// Fewest occurrences a key needs for its record to be written
+    min_count: u64,
+    /// Most occurrences a key may have for its record to be written
+    max_count: Option<u64>,
+    mode: SetMode,
+    /// Number of inputs, which intersections are evaluated against
+    inputs: u32,
+    /// Bytes of records to hold in memory before writing a temporary file
+    memory_limit: usize,
+    temp_dirs: Vec<PathBuf>,
+    space_check: bool,
+    /// Threads in the global pool that chunks are sorted on
+    threads: usize,
+    /// Most temporary files to merge at once
+    merge_fanin: usize,
+}
+
+impl DedupOptions {
+    /// Builds the options from the command line arguments, after the thread pool is set up
+    fn new(args: &Cli) -> Result<Self, String> {
+        let delimiter = args.delimiter.unwrap_or(match args.format {
+            Format::Csv => ',',
+            Format::Lines | Format::Jsonl => '\t',
+        });
+        let key = KeyExtractor::new(args.format, &args.key, delimiter)?;
+        Ok(DedupOptions {
+            format: args.format,
+            compress: args.compress,
+            temp_compression: args.temp_compression.into(),
+            header: args.header,
+            encoding: args.encoding,
+            on_invalid_utf8: args.on_invalid_utf8,
+            keep_order: args.keep_order,
+            keep: args.keep,
+            key,
+            normalization: Normalization {
+                unicode: args.unicode_normalize,
+                ignore_case: args.ignore_case,
+                casefold: args.casefold,
+                trim: args.trim,
+                squeeze_whitespace: args.squeeze_whitespace,
+                ignore_line_endings: args.ignore_line_endings,
+            },
+            count: args.count.then_some(args.count_format),
+            delimiter,
+            record_separator: match (args.zero_terminated, args.record_separator.clone()) {
+                (true, _) => vec![0],
+                (false, separator) => separator.unwrap_or_else(|| b"\n".to_vec()),
+            },
+            grouping: match args.record_start.clone() {
+                Some(start) => Grouping::Start(start),
+                None if args.paragraph => Grouping::Paragraph,
+                None => Grouping::Line,
+            },
+            line_ending: args.line_ending,
+            // --only-duplicates and --only-unique are shorthands for count bounds; when
+            // combined with explicit bounds the stricter one applies
+            min_count: args.min_count.unwrap_or(1).max(if args.only_duplicates { 2 } else { 1 }),
+            max_count: match (args.max_count, args.only_unique) {
+                (Some(max), true) => Some(max.min(1)),
+                (max, only_unique) => max.or(only_unique.then_some(1)),
+            },
+            mode: args.mode,
+            inputs: args.input.len() as u32,
+            memory_limit: args.memory_limit.unwrap_or_else(default_memory_limit),
+            temp_dirs: args.temp_dir.clone(),
+            space_check: !args.no_space_check,
+            threads: rayon::current_num_threads(),
+            merge_fanin: args.merge_fanin.map_or_else(default_merge_fanin, |fanin| fanin as usize),
+        })
+    }
+
+    /// Whether a fully merged line passes the occurrence count filters and set operation
+    fn selects(&self, line: &Line) -> bool {
+        let in_set = match self.mode {
+            SetMode::Union => true,
+            SetMode::Intersect => line.sources == self.inputs,
+            SetMode::Subtract => line.sources == 1 && line.source == 0,
+            SetMode::Symdiff => line.sources == 1,
+        };
+        in_set && line.count >= self.min_count && self.max_count.is_none_or(|max| line.count <= max)
+    }
+
+    /// Whether records are interpreted as text, and so must be valid UTF-8
+    fn reads_text(&self) -> bool {
+        self.format != Format::Lines || self.normalization.transforms()
+    }
+
+    /// Whether entries from different inputs must be kept apart until the final merge
+    fn tracks_sources(&self) -> bool {
+        self.mode != SetMode::Union
+    }
+}
+
+/// Removes duplicate lines from the input files using an external merge sort and writes the result to `output_path`
+///
+/// `inputs` holds the files of each input argument; records are tagged with the index of
+/// the argument they came from.
+fn remove_duplicates_large_file(
+    inputs: &[Vec<PathBuf>],
+    output_path: &str,
+    options: &DedupOptions,
+) -> std::io::Result<()> {
+    // Progress is drawn on stderr so that output written to stdout stays clean.
+    // Standard input and pipes can only be read once, so they are not counted up front
+    // and progress is shown without a total.
+    let total_lines = if inputs.iter().flatten().all(|path| input::is_seekable(path)) {
+        Some(count_lines(inputs, options)?)
+    } else {
+        None
+    };
+
+    // Open the output file, compressing it as requested or as its extension suggests. A file
+    // is written beside the output and moved into place once deduplication succeeds, so that
+    // the output may also be an input and a failed run leaves an existing output untouched.
+    let mut pending_output = None;
+    let (output, compression): (Box<dyn Write>, _) = if output_path == "-" {
+        (Box::new(io::stdout().lock()), options.compress.unwrap_or(Compression::None))
+    } else {
+        let compression = options.compress.unwrap_or_else(|| Compression::from_extension(Path::new(output_path)));
+        let (file, path) = open_output_file(Path::new(output_path))?;
+        pending_output = path;
+        (Box::new(file), compression)
+    };
+    let terminator = match options.line_ending {
+        Some(LineEnding::Lf) => b"\n".to_vec(),
+        Some(LineEnding::Crlf) => b"\r\n".to_vec(),
+        None => options.record_separator.clone(),
+    };
+    let blank_lines = matches!(options.grouping, Grouping::Paragraph);
+    let mut writer = RecordWriter::new(BufWriter::new(compression.encoder(output)?), terminator, blank_lines);
+
+    // Set up a progress bar for processing
+    let progress_bar = match total_lines {
+        Some(total_lines) => {
+            let progress_bar = ProgressBar::with_draw_target(Some(total_lines), ProgressDrawTarget::stderr());
+            progress_bar.set_style(
+                ProgressStyle::default_bar()
+                    .template(
+                        "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} lines ({percent}%) | {msg}",
+                    )
+                    .unwrap()
+                    .progress_chars("#>-"),
+            );
+            progress_bar
+        }
+        None => {
+            let progress_bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
+            progress_bar.set_style(
+                ProgressStyle::with_template("{spinner:.green} [{elapsed_precise}] {pos} lines | {msg}")
+                    .unwrap()
+                    .tick_strings(&["-", "\\", "|", "/"]),
+            );
+            progress_bar
+        }
+    };
+    progress_bar.tick();
+
+    // Create the temporary directories, making sure the runs will fit before doing any work
+    // or, for input that may never be spilled, before the first run is written
+    let scratch = ScratchDirs::new(&options.temp_dirs)?;
+    let mut deferred_space_check = check_scratch_space(&scratch, inputs, total_lines, options)?;
+    let mut check_space_on_spill = || match deferred_space_check.take() {
+        Some(required) => scratch.check_space(required),
+        None => Ok(()),
+    };
+
+    let (temp_files, distinct) = if options.threads > 1 {
+        // Sort and write each chunk on a worker thread while the next one is read. The
+        // channel holds no chunks itself, so at most two are in memory at once and each
+        // gets half the budget. Nothing is sorted while records are deduplicated in memory,
+        // so those get all of it.
+        thread::scope(|scope| {
+            let (sender, receiver) = mpsc::sync_channel::<Vec<Line>>(0);
+            let scratch = &scratch;
+            let worker = scope.spawn(move || {
+                receiver
+                    .into_iter()
+                    .map(|mut chunk| process_chunk(&mut chunk, scratch, options))
+                    .collect::<io::Result<Vec<_>>>()
+            });
+            let read = read_chunks(inputs, &mut writer, &progress_bar, options, options.memory_limit / 2, |chunk| {
+                check_space_on_spill()?;
+                sender.send(chunk).map_err(|_| io::Error::other("chunk sorting stopped unexpectedly"))
+            });
+            drop(sender);
+            // A failed worker stops the reading, so report its error before the reader's
+            let temp_files = worker.join().expect("chunk sorting thread panicked")?;
+            Ok::<_, io::Error>((temp_files, read?))
+        })?
+    } else {
+        let mut temp_files = Vec::new();
+        let distinct = read_chunks(inputs, &mut writer, &progress_bar, options, options.memory_limit, |mut chunk| {
+            check_space_on_spill()?;
+            temp_files.push(process_chunk(&mut chunk, &scratch, options)?);
+            Ok(())
+        })?;
+        (temp_files, distinct)
+    };
+
+    progress_bar.finish_with_message("File reading complete.");
+    std::mem::drop(progress_bar); // Discard the first progress bar
+    // new progress bar for merging
+    let progress_bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
     progress_bar.set_style(
         ProgressStyle::with_template("{spinner:.green} {msg}")
             .unwrap()
             .tick_strings(&["-", "\\", "|", "/"]),
     );
     progress_bar.enable_steady_tick(std::time::Duration::from_millis(100));
-    progress_bar.set_message("Counting Lines...");
+    progress_bar.set_message("Merging Temporary Files...");
     progress_bar.tick();
-    io::stdout().flush().unwrap();
 
-    // Count total lines in the input file
-    let input_file = File::open(input_path)?;
-    let reader = BufReader::new(&input_file);
-    let total_lines = reader.lines().count() as u64;
-    progress_bar.finish_with_message(format!("Count complete. {} lines.", total_lines));
-    std::mem::drop(progress_bar); // Discard the first progress bar
+    if let Some(distinct) = distinct {
+        // Every distinct record fit in memory, so there are no temporary files to merge
+        progress_bar.set_message("Sorting Distinct Lines...");
+        write_distinct_lines(distinct.into_lines(), &mut writer, options)?;
+    } else if options.keep_order {
+        // The merge yields surviving lines sorted by key; sort them back into
+        // input order with a second external sort on their line numbers
+        progress_bar.set_message("Restoring Original Line Order...");
+        let temp_files = merge_sorted_files_into_runs(temp_files, &scratch, options)?;
+        write_lines_in_order(temp_files, &mut writer, &scratch, options)?;
+    } else {
+        merge_sorted_files(temp_files, &mut writer, &scratch, options)?;
+    }
+    writer.finish()?.into_inner().map_err(|e| e.into_error())?.finish()?.flush()?;
+    if let Some(path) = pending_output {
+        path.persist(output_path).map_err(|e| e.error)?;
+    }
+    progress_bar.finish_with_message("Deduplication completed successfully.");
+    Ok(())
+}
 
-    // Re-open file for processing
-    let input_file = File::open(input_path)?;
-    let reader = BufReader::new(input_file);
+/// Opens the output file, returning the path to rename it to once it is complete
+///
+/// A regular file, or a path that does not exist yet, is written beside its final path and
+/// renamed over it on success, so that a failed run leaves it untouched and it may also be
+/// an input. Anything else, such as a FIFO, a device or a symlink, is written in place.
+fn open_output_file(path: &Path) -> io::Result<(File, Option<TempPath>)> {
+    match std::fs::symlink_metadata(path) {
+        Ok(metadata) if !metadata.file_type().is_file() => Ok((File::create(path)?, None)),
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => {
+            let (file, temp_path) = create_output_file(path)?.into_parts();
+            Ok((file, Some(temp_path)))
+        }
+    }
+}
 
-    // Set up a progress bar for processing
-    let progress_bar = ProgressBar::new(total_lines);
-    progress_bar.set_style(
-        ProgressStyle::default_bar()
-            .template(
-                "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} lines ({percent}%) | {msg}",
-            )
-            .unwrap()
-            .progress_chars("#>-"),
-    );
-    progress_bar.tick();
-    io::stdout().flush().unwrap();
+/// Creates the file the output is written to before it replaces `path`, in the same
+/// directory so that it can be renamed into place
+///
+/// The file gets the permissions of the file it replaces, if any.
+fn create_output_file(path: &Path) -> io::Result<NamedTempFile> {
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let mut builder = tempfile::Builder::new();
+    builder.prefix(".dedup-");
+    match std::fs::metadata(path) {
+        Ok(metadata) => {
+            builder.permissions(metadata.permissions());
+        }
+        // Temporary files are private by default; new outputs are readable like any other file
+        #[cfg(unix)]
+        Err(_) => {
+            use std::os::unix::fs::PermissionsExt;
+            builder.permissions(std::fs::Permissions::from_mode(0o644));
+        }
+        #[cfg(not(unix))]
+        Err(_) => {}
+    }
+    builder.tempfile_in(dir)
+}
 
-    // Create a temporary directory and initialize state variables
-    let temp_dir = tempfile::tempdir()?;
-    let mut temp_files = Vec::new();
-    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
-    let mut lines_processed = 0;
+/// Reads the records of all inputs, copying the first header to `writer` and matching
+/// its line endings to the input's
+///
+/// Records are deduplicated in memory for as long as the distinct ones fit in the memory
+/// limit, and returned if they all do. Otherwise they are handed to `process_chunk` in
+/// chunks of about `chunk_limit` bytes, and `None` is returned.
+fn read_chunks(
+    inputs: &[Vec<PathBuf>],
+    writer: &mut RecordWriter<impl Write>,
+    progress_bar: &ProgressBar,
+    options: &DedupOptions,
+    chunk_limit: usize,
+    mut process_chunk: impl FnMut(Vec<Line>) -> io::Result<()>,
+) -> io::Result<Option<DistinctLines>> {
+    const PROGRESS_INTERVAL: u64 = 1 << 16;
 
-    // Process the input file line by line
-    for line_result in reader.lines() {
-        let line = line_result?;
-        chunk.push(line); // Add line to chunk if not seen before
+    // Initialize state variables
+    let mut distinct = Some(DistinctLines::new(options.keep, options.tracks_sources()));
+    let mut chunk = Vec::new();
+    let mut chunk_bytes = 0;
+    // Records are numbered across all inputs, so numbers also order records by input
+    let mut number = 0;
+    let mut skipped = 0;
 
-        // Process the chunk when it reaches the specified size
-        if chunk.len() >= CHUNK_SIZE {
-            let temp_file = process_chunk_sequential(&chunk, temp_dir.path())?;
-            temp_files.push(temp_file);
-            chunk.clear(); // Clear chunk after processing
-            lines_processed += CHUNK_SIZE as u64;
-            progress_bar.set_position(lines_processed);
+    let input_paths = inputs
+        .iter()
+        .enumerate()
+        .flat_map(|(source, files)| files.iter().map(move |path| (source, path)));
+    for (file_index, (source, input_path)) in input_paths.enumerate() {
+        let input_file = input::open_input(input_path, options.encoding).map_err(|e| with_path(e, input_path))?;
+        let mut reader = RecordReader::new(input_file, options.format, &options.record_separator, options.grouping.clone());
+
+        // Copy the header of the first file, which takes no part in deduplication, and
+        // skip the headers of the others
+        if options.header {
+            if let Some(header) = reader.next_record()? {
+                if file_index == 0 {
+                    // The count gets a header of its own so that columns stay aligned
+                    write_record(writer, &header, "count", options)?;
+                }
+            }
+        }
+
+        // Process the input file record by record, tagging each record with its number, input and key
+        for (index, line_result) in reader.by_ref().enumerate() {
+            let mut text = line_result.map_err(|e| with_path(e, input_path))?;
+            let record_error = |e: io::Error| {
+                io::Error::new(e.kind(), format!("{}: record {}: {}", input_path.display(), index + 1, e))
+            };
+            // Blank lines hold no JSON value, so JSON Lines readers pass over them
+            if options.format == Format::Jsonl && text.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            if options.reads_text() {
+                if let Err(e) = std::str::from_utf8(&text) {
+                    match options.on_invalid_utf8 {
+                        InvalidUtf8::Skip => {
+                            skipped += 1;
+                            continue;
+                        }
+                        InvalidUtf8::Replace => text = String::from_utf8_lossy(&text).into_owned().into_bytes(),
+                        InvalidUtf8::Error => {
+                            return Err(record_error(io::Error::new(io::ErrorKind::InvalidData, e)));
+                        }
+                    }
+                }
+            }
+            let key = options.key.extract(&text).map_err(record_error)?;
+            let key = options.normalization.apply(key, &text);
+            let line = Line { key, text, number, count: 1, source: source as u32, sources: 1 };
+            number += 1;
+            if number % PROGRESS_INTERVAL == 0 {
+                progress_bar.set_position(number);
+            }
+
+            if let Some(lines) = &mut distinct {
+                lines.insert(line);
+                // Once the distinct records outgrow the memory budget, fall back to sorting
+                // them in chunks, starting with those deduplicated so far
+                if lines.memory_size() >= options.memory_limit {
+                    process_chunk(distinct.take().expect("checked above").into_lines())?;
+                }
+                continue;
+            }
+            chunk_bytes += line.memory_size();
+            chunk.push(line);
+
+            // Process the chunk when it fills the memory budget
+            if chunk_bytes >= chunk_limit {
+                process_chunk(std::mem::take(&mut chunk))?;
+                chunk_bytes = 0;
+            }
+        }
+
+        // Write the line endings of the first input, and end the output like the last one
+        if file_index == 0 && options.line_ending.is_none() && reader.crlf() {
+            writer.set_terminator(b"\r\n");
         }
+        if let Some(terminated) = reader.terminated() {
+            writer.set_terminate_last(terminated);
+        }
+    }
+    progress_bar.set_position(number);
+    if skipped > 0 {
+        progress_bar.suspend(|| eprintln!("Warning: skipped {} records that are not valid UTF-8", skipped));
+    }
+    if distinct.is_some() {
+        return Ok(distinct);
     }
 
     // Process any remaining lines in the last chunk
     if !chunk.is_empty() {
-        let temp_file = process_chunk_sequential(&chunk, temp_dir.path())?;
-        temp_files.push(temp_file);
+        process_chunk(chunk)?;
     }
+    Ok(None)
+}
 
-    progress_bar.finish_with_message("File reading complete. Merging files...");
-    std::mem::drop(progress_bar); // Discard the first progress bar
-    // new progress bar for merging
-    let progress_bar = ProgressBar::new_spinner();
+/// Fails if the temporary files needed to deduplicate the inputs will not fit
+///
+/// Input smaller than the memory budget may be deduplicated without writing any temporary
+/// files, so it is not checked here; the space it would need is returned instead, for
+/// checking once the first temporary file is about to be written.
+fn check_scratch_space(
+    scratch: &ScratchDirs,
+    inputs: &[Vec<PathBuf>],
+    total_lines: Option<u64>,
+    options: &DedupOptions,
+) -> io::Result<Option<u64>> {
+    if !options.space_check {
+        return Ok(None);
+    }
+    let Some(required) = estimate_scratch_space(inputs, total_lines, options) else {
+        return Ok(None);
+    };
+    if estimated_input_size(inputs).is_some_and(|size| size < options.memory_limit as u64) {
+        return Ok(Some(required));
+    }
+    scratch.check_space(required)?;
+    Ok(None)
+}
+
+/// Estimates the bytes held by all inputs once decompressed, or `None` when an input's
+/// size cannot be known in advance
+fn estimated_input_size(inputs: &[Vec<PathBuf>]) -> Option<u64> {
+    inputs.iter().flatten().map(|path| input::estimated_size(path)).sum()
+}
+
+/// Estimates the bytes of temporary files needed to deduplicate the inputs, or `None`
+/// when an input's size cannot be known in advance
+fn estimate_scratch_space(inputs: &[Vec<PathBuf>], total_lines: Option<u64>, options: &DedupOptions) -> Option<u64> {
+    // Each entry of a run stores its record plus a few numeric fields, and a key when
+    // one is extracted, which at worst repeats the whole record
+    const ENTRY_OVERHEAD: u64 = 24;
+    let mut required = estimated_input_size(inputs)?;
+    if !matches!(options.key, KeyExtractor::WholeLine) || options.normalization.transforms() {
+        required *= 2;
+    }
+    required += total_lines? * ENTRY_OVERHEAD;
+    // Re-sorting by position writes a second set of runs while the first is still on disk
+    if options.keep_order {
+        required *= 2;
+    }
+    // Compressed runs of sorted, front-coded text typically take well under half the space
+    if options.temp_compression != Compression::None {
+        required /= 2;
+    }
+    Some(required)
+}
+
+/// Counts the records in the input files, showing a spinner while counting
+fn count_lines(inputs: &[Vec<PathBuf>], options: &DedupOptions) -> std::io::Result<u64> {
+    // Initialize a spinner to count lines
+    let progress_bar = ProgressBar::with_draw_target(None, ProgressDrawTarget::stderr());
     progress_bar.set_style(
         ProgressStyle::with_template("{spinner:.green} {msg}")
             .unwrap()
             .tick_strings(&["-", "\\", "|", "/"]),
     );
     progress_bar.enable_steady_tick(std::time::Duration::from_millis(100));
-    progress_bar.set_message("Merging Temporary Files...");
+    progress_bar.set_message("Counting Lines...");
     progress_bar.tick();
-    io::stdout().flush().unwrap();
 
-    merge_sorted_files(temp_files, output_path)?;
-    progress_bar.finish_with_message("Deduplication completed successfully.");
-    Ok(())
+    let mut total_lines = 0;
+    for input_path in inputs.iter().flatten() {
+        let input_file = input::open_input(input_path, options.encoding).map_err(|e| with_path(e, input_path))?;
+        for record in RecordReader::new(input_file, options.format, &options.record_separator, options.grouping.clone()) {
+            record.map_err(|e| with_path(e, input_path))?; // Stop on read errors rather than counting them
+            total_lines += 1;
+        }
+    }
+    progress_bar.finish_with_message(format!("Count complete. {} lines.", total_lines));
+    Ok(total_lines)
 }
 
-/// Processes a single chunk sequentially by deduplicating and writing it to a temporary file
-fn process_chunk_sequential<'a>(
-    chunk: &'a [String],
-    temp_dir: &Path,
-) -> std::io::Result<NamedTempFile> {
-    // Sort and deduplicate lines within the chunk
-    let mut lines = chunk.to_vec();
-    lines.sort();
-    lines.dedup();
+/// Prefixes an error with the path of the file it occurred in
+fn with_path(error: io::Error, path: &Path) -> io::Error {
+    io::Error::new(error.kind(), format!("{}: {}", path.display(), error))
+}
 
-    // Write deduplicated lines to a temporary file
-    let temp_file = NamedTempFile::new_in(temp_dir)?;
-    {
-        let mut writer = std::io::BufWriter::new(temp_file.as_file());
-        for line in lines {
-            writeln!(writer, "{}", line)?;
-        }
-        writer.flush()?;
+/// Sorts a chunk, in parallel on the global thread pool when it has more than one thread
+fn sort_chunk(chunk: &mut [Line], order: SortOrder, options: &DedupOptions) {
+    if options.threads > 1 {
+        chunk.par_sort_unstable_by(|a, b| order.compare(a, b));
+    } else {
+        chunk.sort_unstable_by(|a, b| order.compare(a, b));
     }
-    Ok(temp_file)
 }
 
-fn merge_sorted_files(temp_files: Vec<NamedTempFile>, output_path: &str) -> std::io::Result<()> {
-    //K-way Merge Algorithm (a.k.a External Merge Sort)
-    // Create a vector of `BufReader`s, one for each temporary file
-    // These readers will allow reading lines from each file one at a time
-    let mut readers = temp_files
-        .into_iter()
-        .map(|file| BufReader::new(File::open(file.path()).unwrap()))
-        .collect::<Vec<_>>();
+/// Processes a single chunk by sorting, deduplicating and writing it to a temporary file
+fn process_chunk(
+    chunk: &mut Vec<Line>,
+    scratch: &ScratchDirs,
+    options: &DedupOptions,
+) -> std::io::Result<TempPath> {
+    // Sort by key then line number, so each group of duplicates is ordered by position.
+    // Line numbers are unique, so an unstable sort gives the same order without the
+    // extra memory a stable sort allocates.
+    sort_chunk(chunk, SortOrder::Key, options);
+    chunk.dedup_by(|line, previous| {
+        // Set operations need one entry per key and input to count distinct inputs in the merge
+        if line.key() != previous.key() || (options.tracks_sources() && line.source != previous.source) {
+            return false;
+        }
+        previous.absorb(std::mem::take(line), options.keep);
+        true
+    });
+    write_run(chunk, scratch.next_dir(), options.temp_compression)
+}
 
-    // Open the output file where the deduplicated and sorted lines will be written
-    let output_file = File::create(output_path)?;
-    let mut writer = std::io::BufWriter::new(output_file);
+/// Writes records deduplicated in memory, in the order merging temporary files would give
+fn write_distinct_lines(
+    mut lines: Vec<Line>,
+    writer: &mut RecordWriter<impl Write>,
+    options: &DedupOptions,
+) -> std::io::Result<()> {
+    sort_chunk(&mut lines, SortOrder::Key, options);
+    // Entries are unique per key and input, so each duplicate folded in adds its inputs
+    lines.dedup_by(|line, previous| {
+        if line.key() != previous.key() {
+            return false;
+        }
+        previous.sources += line.sources;
+        previous.absorb(std::mem::take(line), options.keep);
+        true
+    });
+    lines.retain(|line| options.selects(line));
+    if options.keep_order {
+        sort_chunk(&mut lines, SortOrder::Position, options);
+    }
+
+    for line in &lines {
+        write_line(writer, line, options)?;
+    }
+    writer.flush()?;
+    Ok(())
+}
 
-    // Use a binary heap to maintain the smallest (lexicographically first) line
-    // from the multiple readers. The heap is reversed (`std::cmp::Reverse`)
-    // because Rust's `BinaryHeap` is a max-heap by default.
-    let mut heap = std::collections::BinaryHeap::new();
+/// Merges temporary files in groups of at most `options.merge_fanin` into fewer, larger
+/// ones, until they are few enough to be merged at once
+///
+/// Runs sorted by key are combined as chunks are, so that intermediate runs do not grow
+/// with duplicates.
+fn reduce_runs(
+    mut temp_files: Vec<TempPath>,
+    order: SortOrder,
+    scratch: &ScratchDirs,
+    options: &DedupOptions,
+) -> std::io::Result<Vec<TempPath>> {
+    while temp_files.len() > options.merge_fanin {
+        let mut merged = Vec::new();
+        while !temp_files.is_empty() {
+            let group: Vec<_> = temp_files.drain(..options.merge_fanin.min(temp_files.len())).collect();
+            if group.len() == 1 {
+                merged.extend(group);
+                continue;
+            }
+            let mut merger = RunMerger::new(group, order, options.temp_compression)?;
+            let mut run = RunWriter::new(scratch.next_dir(), options.temp_compression)?;
+            match order {
+                SortOrder::Key => {
+                    while let Some(line) = merger.next_combined(options.keep, options.tracks_sources())? {
+                        run.write_line(&line)?;
+                    }
+                }
+                SortOrder::Position => {
+                    while let Some(line) = merger.next_line()? {
+                        run.write_line(&line)?;
+                    }
+                }
+            }
+            merged.push(run.finish()?);
+        }
+        temp_files = merged;
+    }
+    Ok(temp_files)
+}
 
-    // Initialize the heap with the first line from each reader
-    for (index, reader) in readers.iter_mut().enumerate() {
-        let mut line = String::new();
-        if reader.read_line(&mut line)? > 0 { // If a line was successfully read
-            heap.push((std::cmp::Reverse(line.clone()), index)); // Push the line and reader index to the heap
+fn merge_sorted_files(
+    temp_files: Vec<TempPath>,
+    writer: &mut RecordWriter<impl Write>,
+    scratch: &ScratchDirs,
+    options: &DedupOptions,
+) -> std::io::Result<()> {
+    let temp_files = reduce_runs(temp_files, SortOrder::Key, scratch, options)?;
+    //K-way Merge Algorithm (a.k.a External Merge Sort)
+    // Lines come out of the merger sorted by key, then by line number, so all
+    // duplicates of a line are adjacent and ordered by their position in the input
+    let mut merger = RunMerger::new(temp_files, SortOrder::Key, options.temp_compression)?;
+
+    // Continue processing until every reader is exhausted, writing one line per distinct key
+    while let Some(line) = merger.next_distinct(options.keep)? {
+        if options.selects(&line) {
+            write_line(writer, &line, options)?;
         }
     }
 
-    // Variable to track the last line written to avoid duplicates
-    let mut last_line = String::new();
+    // Flush the writer to ensure all lines are written to the output file
+    writer.flush()?;
+    Ok(())
+}
+
+/// Merges sorted temporary files and re-sorts the surviving occurrences by line
+/// number, returning new temporary files sorted in original input order
+fn merge_sorted_files_into_runs(
+    temp_files: Vec<TempPath>,
+    scratch: &ScratchDirs,
+    options: &DedupOptions,
+) -> std::io::Result<Vec<TempPath>> {
+    let temp_files = reduce_runs(temp_files, SortOrder::Key, scratch, options)?;
+    let mut merger = RunMerger::new(temp_files, SortOrder::Key, options.temp_compression)?;
+    let mut runs = Vec::new();
+    let mut chunk: Vec<Line> = Vec::new();
+    let mut chunk_bytes = 0;
 
-    // Continue processing until the heap is empty
-    while let Some((std::cmp::Reverse(line), index)) = heap.pop() {
-        // If the current line is different from the last line written, write it to the output
-        if line != last_line {
-            writeln!(writer, "{}", line)?;
-            last_line = line; // Update the last line
+    while let Some(mut line) = merger.next_distinct(options.keep)? {
+        if !options.selects(&line) {
+            continue;
         }
+        // Keys are no longer needed once duplicates have been resolved
+        line.key = None;
+        chunk_bytes += line.memory_size();
+        chunk.push(line);
 
-        // Attempt to read the next line from the reader that produced the current line
-        let mut new_line = String::new();
-        if readers[index].read_line(&mut new_line)? > 0 { // If a line was successfully read
-            heap.push((std::cmp::Reverse(new_line.clone()), index)); // Push it back to the heap
+        if chunk_bytes >= options.memory_limit {
+            sort_chunk(&mut chunk, SortOrder::Position, options);
+            runs.push(write_run(&chunk, scratch.next_dir(), options.temp_compression)?);
+            chunk.clear();
+            chunk_bytes = 0;
         }
     }
 
-    // Flush the writer to ensure all lines are written to the output file
+    if !chunk.is_empty() {
+        sort_chunk(&mut chunk, SortOrder::Position, options);
+        runs.push(write_run(&chunk, scratch.next_dir(), options.temp_compression)?);
+    }
+    Ok(runs)
+}
+
+/// Merges temporary files sorted by line number and writes their lines to the output
+fn write_lines_in_order(
+    temp_files: Vec<TempPath>,
+    writer: &mut RecordWriter<impl Write>,
+    scratch: &ScratchDirs,
+    options: &DedupOptions,
+) -> std::io::Result<()> {
+    let temp_files = reduce_runs(temp_files, SortOrder::Position, scratch, options)?;
+    let mut merger = RunMerger::new(temp_files, SortOrder::Position, options.temp_compression)?;
+
+    while let Some(line) = merger.next_line()? {
+        write_line(writer, &line, options)?;
+    }
+
     writer.flush()?;
     Ok(())
 }
 
+/// Writes a surviving line to the output, with its occurrence count if requested
+fn write_line(writer: &mut RecordWriter<impl Write>, line: &Line, options: &DedupOptions) -> std::io::Result<()> {
+    write_record(writer, &line.text, line.count, options)
+}
+
+/// Writes a record to the output, followed or preceded by `count` if counts are requested
+fn write_record(
+    writer: &mut RecordWriter<impl Write>,
+    text: &[u8],
+    count: impl std::fmt::Display,
+    options: &DedupOptions,
+) -> std::io::Result<()> {
+    writer.begin_record()?;
+    match options.count {
+        Some(CountFormat::Prefix) => {
+            write!(writer, "{}\t", count)?;
+            writer.write_all(text)?;
+        }
+        Some(CountFormat::Column) => {
+            writer.write_all(text)?;
+            write!(writer, "{}{}", options.delimiter, count)?;
+        }
+        None => writer.write_all(text)?,
+    }
+    Ok(())
+}
+
 fn main() {
     let args = Cli::parse();
+    let threads = rayon::ThreadPoolBuilder::new().num_threads(args.threads.unwrap_or(0)).build_global();
+    if let Err(e) = threads {
+        eprintln!("Error: {}", e);
+        std::process::exit(2);
+    }
+    let options = match DedupOptions::new(&args) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            std::process::exit(2);
+        }
+    };
 
-
-    if let Err(e) = remove_duplicates_large_file(&args.input, &args.output) {
+    let inputs = args
+        .input
+        .iter()
+        .map(|input| input::expand_input(input))
+        .collect::<std::io::Result<Vec<_>>>();
+    let result = inputs.and_then(|inputs| remove_duplicates_large_file(&inputs, &args.output, &options));
+    if let Err(e) = result {
         eprintln!("Error: {}", e);
         std::process::exit(1);
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(args: &[&str]) -> DedupOptions {
+        let args = Cli::parse_from(["deduplicate", "-i", "-", "-o", "-"].iter().chain(args));
+        DedupOptions::new(&args).unwrap()
+    }
+
+    #[test]
+    fn space_check_deferred_for_input_under_memory_limit() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("input");
+        std::fs::write(&path, "b\na\nb\n".repeat(1000)).unwrap();
+        let inputs = vec![vec![path]];
+        let scratch = ScratchDirs::new(&[dir.path().to_path_buf()]).unwrap();
+
+        let fits = options(&["--memory-limit", "1G", "-T", dir.path().to_str().unwrap()]);
+        let deferred = check_scratch_space(&scratch, &inputs, Some(3000), &fits).unwrap();
+        assert_eq!(deferred, estimate_scratch_space(&inputs, Some(3000), &fits));
+        assert!(deferred.is_some());
+
+        let spills = options(&["--memory-limit", "1K", "-T", dir.path().to_str().unwrap()]);
+        assert_eq!(check_scratch_space(&scratch, &inputs, Some(3000), &spills).unwrap(), None);
+
+        let unchecked = options(&["--memory-limit", "1K", "--no-space-check"]);
+        assert_eq!(check_scratch_space(&scratch, &inputs, Some(3000), &unchecked).unwrap(), None);
+    }
+
+    #[test]
+    fn input_under_memory_limit_deduplicated_without_temporary_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+        let scratch = dir.path().join("scratch");
+        std::fs::create_dir(&scratch).unwrap();
+        std::fs::write(&input, "b\na\nb\n".repeat(1000)).unwrap();
+
+        let options = options(&["--memory-limit", "1G", "-T", scratch.to_str().unwrap()]);
+        remove_duplicates_large_file(&[vec![input]], output.to_str().unwrap(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "a\nb\n");
+        assert_eq!(std::fs::read_dir(&scratch).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn jsonl_blank_lines_skipped() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        let output = dir.path().join("output");
+        std::fs::write(&input, "{\"a\":1}\n\n \t\n{\"a\": 1}\n{\"a\":2}\n\n").unwrap();
+
+        let options = options(&["--format", "jsonl", "--keep-order", "-T", dir.path().to_str().unwrap()]);
+        remove_duplicates_large_file(&[vec![input]], output.to_str().unwrap(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(&output).unwrap(), "{\"a\":1}\n{\"a\":2}\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn output_symlink_written_through() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("input");
+        let target = dir.path().join("target");
+        let link = dir.path().join("link");
+        std::fs::write(&input, "b\na\nb\n").unwrap();
+        std::fs::write(&target, "old\n").unwrap();
+        std::os::unix::fs::symlink(&target, &link).unwrap();
+
+        let options = options(&["-T", dir.path().to_str().unwrap()]);
+        remove_duplicates_large_file(&[vec![input]], link.to_str().unwrap(), &options).unwrap();
+        assert!(std::fs::symlink_metadata(&link).unwrap().file_type().is_symlink());
+        assert_eq!(std::fs::read_to_string(&target).unwrap(), "a\nb\n");
+    }
+
+    #[test]
+    fn separator_escapes() {
+        assert_eq!(parse_separator(";").unwrap(), b";");
+        assert_eq!(parse_separator("--").unwrap(), b"--");
+        assert_eq!(parse_separator(r"\0").unwrap(), b"\0");
+        assert_eq!(parse_separator(r"\r\n").unwrap(), b"\r\n");
+        assert_eq!(parse_separator(r"\t|\\").unwrap(), b"\t|\\");
+        assert_eq!(parse_separator(r"\x1e\xFF").unwrap(), b"\x1e\xff");
+        assert_eq!(parse_separator("é").unwrap(), "é".as_bytes());
+    }
+
+    #[test]
+    fn separator_errors() {
+        assert!(parse_separator("").is_err());
+        assert!(parse_separator(r"\").is_err());
+        assert!(parse_separator(r"\q").is_err());
+        assert!(parse_separator(r"\x").is_err());
+        assert!(parse_separator(r"\x1").is_err());
+        assert!(parse_separator(r"\xg0").is_err());
+    }
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("0").unwrap(), 0);
+        assert_eq!(parse_size("300").unwrap(), 300);
+        assert_eq!(parse_size("300B").unwrap(), 300);
+        assert_eq!(parse_size("64K").unwrap(), 64 << 10);
+        assert_eq!(parse_size("64kb").unwrap(), 64 << 10);
+        assert_eq!(parse_size("512MiB").unwrap(), 512 << 20);
+        assert_eq!(parse_size(" 2 G ").unwrap(), 2 << 30);
+        assert_eq!(parse_size("1.5G").unwrap(), 3 << 29);
+        assert_eq!(parse_size("1T").unwrap(), 1 << 40);
+    }
+
+    #[test]
+    fn size_errors() {
+        assert!(parse_size("").is_err());
+        assert!(parse_size("G").is_err());
+        assert!(parse_size("1.2.3M").is_err());
+        assert!(parse_size("-1M").is_err());
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("10 MBs").is_err());
+    }
+}
@@ -0,0 +1,59 @@
+use std::io::{self, Write};
+
+/// Writes records to the output, holding back each terminator until the next record so
+/// that the last record can be left unterminated, like the input it came from
+pub struct RecordWriter<W> {
+    writer: W,
+    terminator: Vec<u8>,
+    /// Leave a blank line between records, as between paragraphs
+    blank_lines: bool,
+    /// Whether a record has been written whose terminator is still owed
+    pending: bool,
+    /// Whether the last record gets a terminator
+    terminate_last: bool,
+}
+
+impl<W: Write> RecordWriter<W> {
+    pub fn new(writer: W, terminator: Vec<u8>, blank_lines: bool) -> Self {
+        RecordWriter { writer, terminator, blank_lines, pending: false, terminate_last: true }
+    }
+
+    /// Replaces the terminator, which takes effect for every record not yet terminated
+    pub fn set_terminator(&mut self, terminator: &[u8]) {
+        self.terminator = terminator.to_vec();
+    }
+
+    pub fn set_terminate_last(&mut self, terminate_last: bool) {
+        self.terminate_last = terminate_last;
+    }
+
+    /// Terminates the previous record, if any, before a new one is written
+    pub fn begin_record(&mut self) -> io::Result<()> {
+        if self.pending {
+            self.writer.write_all(&self.terminator)?;
+            if self.blank_lines {
+                self.writer.write_all(&self.terminator)?;
+            }
+        }
+        self.pending = true;
+        Ok(())
+    }
+
+    /// Terminates the last record if required and returns the underlying writer
+    pub fn finish(mut self) -> io::Result<W> {
+        if self.pending && self.terminate_last {
+            self.writer.write_all(&self.terminator)?;
+        }
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write> Write for RecordWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writer.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
@@ -0,0 +1,223 @@
+use clap::ValueEnum;
+use regex::bytes::Regex;
+use std::io::{self, BufRead};
+
+/// Layout of the records in the input
+#[derive(Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Format {
+    /// One record per line
+    Lines,
+    /// RFC 4180 CSV, where quoted fields may contain delimiters, escaped quotes and newlines
+    Csv,
+    /// JSON Lines, one JSON value per line
+    Jsonl,
+}
+
+/// How the lines of the input are grouped into records
+#[derive(Clone)]
+pub enum Grouping {
+    /// Each line is a record, apart from CSV records spanning lines
+    Line,
+    /// A record starts at each line matching the pattern and runs up to the next one
+    Start(Regex),
+    /// Records are paragraphs, separated by blank lines
+    Paragraph,
+}
+
+/// Splits an input stream into records according to its format
+///
+/// Records are yielded as raw bytes, without their final separator. When the separator is
+/// a newline, a carriage return before it is removed too. Lines grouped into one record
+/// are joined with the separator, keeping their carriage returns.
+pub struct RecordReader<R> {
+    reader: R,
+    format: Format,
+    separator: Vec<u8>,
+    grouping: Grouping,
+    /// Line read past the end of the previous record, which starts the next one, and
+    /// whether it ended with a separator
+    pending: Option<(Vec<u8>, bool)>,
+    /// Whether the first terminated record ended with CRLF, when the separator is a newline
+    crlf: Option<bool>,
+    /// Whether the last record read ended with a separator
+    terminated: Option<bool>,
+}
+
+impl<R: BufRead> RecordReader<R> {
+    /// Creates a reader of lines ending with `separator`, which must not be empty
+    pub fn new(reader: R, format: Format, separator: &[u8], grouping: Grouping) -> Self {
+        RecordReader {
+            reader,
+            format,
+            separator: separator.to_vec(),
+            grouping,
+            pending: None,
+            crlf: None,
+            terminated: None,
+        }
+    }
+
+    /// Reads the next record, returning `None` at end of input
+    pub fn next_record(&mut self) -> io::Result<Option<Vec<u8>>> {
+        let record = match self.grouping {
+            Grouping::Line => self.next_line()?,
+            Grouping::Start(_) => self.next_started_record()?,
+            Grouping::Paragraph => self.next_paragraph()?,
+        };
+        let Some((mut record, terminated)) = record else {
+            return Ok(None);
+        };
+        if terminated && self.separator == b"\n" {
+            let crlf = record.ends_with(b"\r");
+            if crlf {
+                record.pop();
+            }
+            self.crlf.get_or_insert(crlf);
+        }
+        self.terminated = Some(terminated);
+        Ok(Some(record))
+    }
+
+    /// Whether the input has CRLF line endings, judging by the first record with an ending
+    pub fn crlf(&self) -> bool {
+        self.crlf == Some(true)
+    }
+
+    /// Whether the last record read ended with a separator, or `None` before any record
+    pub fn terminated(&self) -> Option<bool> {
+        self.terminated
+    }
+
+    /// Reads a record made of a starting line and the lines up to the next one
+    ///
+    /// Lines before the first start form a record of their own.
+    fn next_started_record(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        let (mut record, mut terminated) = match self.pending.take() {
+            Some(line) => line,
+            None => match self.next_line()? {
+                Some(line) => line,
+                None => return Ok(None),
+            },
+        };
+        while let Some((line, line_terminated)) = self.next_line()? {
+            if self.starts_record(&line) {
+                self.pending = Some((line, line_terminated));
+                break;
+            }
+            record.extend_from_slice(&self.separator);
+            record.extend_from_slice(&line);
+            terminated = line_terminated;
+        }
+        Ok(Some((record, terminated)))
+    }
+
+    /// Whether a line matches the start pattern, ignoring the carriage return of a CRLF
+    /// ending like the records themselves do
+    fn starts_record(&self, line: &[u8]) -> bool {
+        let line = match line.strip_suffix(b"\r") {
+            Some(line) if self.separator == b"\n" => line,
+            _ => line,
+        };
+        matches!(&self.grouping, Grouping::Start(start) if start.is_match(line))
+    }
+
+    /// Reads the lines up to the next blank line, skipping blank lines before them
+    fn next_paragraph(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        let is_blank = |line: &[u8]| line.iter().all(u8::is_ascii_whitespace);
+        let (mut record, mut terminated) = loop {
+            match self.next_line()? {
+                Some((line, _)) if is_blank(&line) => continue,
+                Some(line) => break line,
+                None => return Ok(None),
+            }
+        };
+        while let Some((line, line_terminated)) = self.next_line()? {
+            if is_blank(&line) {
+                break;
+            }
+            record.extend_from_slice(&self.separator);
+            record.extend_from_slice(&line);
+            terminated = line_terminated;
+        }
+        Ok(Some((record, terminated)))
+    }
+
+    /// Reads the next line, or CSV record, without its separator, and whether it had one,
+    /// returning `None` at end of input
+    fn next_line(&mut self) -> io::Result<Option<(Vec<u8>, bool)>> {
+        let mut record = Vec::new();
+        if self.read_until_separator(&mut record)? == 0 {
+            return Ok(None);
+        }
+
+        // A CSV record continues past a separator while a quoted field is open.
+        // Escaped quotes come in pairs, so an odd quote count means the record is incomplete.
+        // Only the newly read bytes are counted, keeping long records linear.
+        if self.format == Format::Csv {
+            let odd_quotes = |bytes: &[u8]| bytes.iter().filter(|&&byte| byte == b'"').count() % 2 == 1;
+            let mut in_quotes = odd_quotes(&record);
+            while in_quotes {
+                let start = record.len();
+                if self.read_until_separator(&mut record)? == 0 {
+                    break; // Unterminated quote at end of input; keep what was read
+                }
+                in_quotes ^= odd_quotes(&record[start..]);
+            }
+        }
+
+        let terminated = record.ends_with(&self.separator);
+        if terminated {
+            record.truncate(record.len() - self.separator.len());
+        }
+        Ok(Some((record, terminated)))
+    }
+
+    /// Appends the input up to and including the next separator to `record`, returning
+    /// the number of bytes read
+    fn read_until_separator(&mut self, record: &mut Vec<u8>) -> io::Result<usize> {
+        let start = record.len();
+        let last = *self.separator.last().expect("record separators are not empty");
+        // Separators longer than a byte are found by reading up to their last byte
+        while self.reader.read_until(last, record)? > 0 && !record[start..].ends_with(&self.separator) {}
+        Ok(record.len() - start)
+    }
+}
+
+impl<R: BufRead> Iterator for RecordReader<R> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(input: &str, format: Format, grouping: Grouping) -> Vec<String> {
+        RecordReader::new(input.as_bytes(), format, b"\n", grouping)
+            .map(|record| String::from_utf8(record.unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn csv_records() {
+        let csv = |input| records(input, Format::Csv, Grouping::Line);
+        assert_eq!(csv("a,b\n\"x,y\",z\n"), ["a,b", "\"x,y\",z"]);
+        assert_eq!(csv("\"say \"\"hi\"\"\",1\nb,2\n"), ["\"say \"\"hi\"\"\",1", "b,2"]);
+        assert_eq!(csv("\"one\ntwo\"\"\nthree\",1\nb,2\n"), ["\"one\ntwo\"\"\nthree\",1", "b,2"]);
+        assert_eq!(csv("\"one\r\ntwo\",1\r\nb,2\r\n"), ["\"one\r\ntwo\",1", "b,2"]);
+        assert_eq!(csv("a,1\n\"open,2\nb,3\n"), ["a,1", "\"open,2\nb,3"]);
+    }
+
+    #[test]
+    fn record_start() {
+        let start = || Grouping::Start(Regex::new("^---$").unwrap());
+        assert_eq!(records("x\n---\na\nb\n---\nc\n", Format::Lines, start()), ["x", "---\na\nb", "---\nc"]);
+        assert_eq!(
+            records("x\r\n---\r\na\r\nb\r\n---\r\nc\r\n", Format::Lines, start()),
+            ["x", "---\r\na\r\nb", "---\r\nc"],
+        );
+    }
+}
@@ -0,0 +1,537 @@
+use crate::compression::{Compression, Encoder};
+use crate::Keep;
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use tempfile::{NamedTempFile, TempPath};
+
+/// A record of the input tagged with its zero-based record number and comparison key
+///
+/// Records are raw bytes and are not required to be valid UTF-8.
+#[derive(Clone, Default)]
+pub struct Line {
+    /// Key extracted from the line, or `None` when the whole line is the key
+    pub key: Option<Vec<u8>>,
+    pub text: Vec<u8>,
+    pub number: u64,
+    /// Number of occurrences of the key this entry stands for
+    pub count: u64,
+    /// Index of the first input the key was found in
+    pub source: u32,
+    /// Number of distinct inputs the key was found in; only tracked for set operations
+    pub sources: u32,
+}
+
+impl Line {
+    /// The value duplicates are detected by
+    pub fn key(&self) -> &[u8] {
+        self.key.as_deref().unwrap_or(&self.text)
+    }
+
+    /// Approximate heap and inline memory held by this line, used to size chunks
+    pub fn memory_size(&self) -> usize {
+        std::mem::size_of::<Line>() + self.text.capacity() + self.key.as_ref().map_or(0, Vec::capacity)
+    }
+
+    /// Folds a later occurrence of the same key into this entry
+    ///
+    /// The counts add up and the entry keeps the input the key was first found in. With
+    /// `Keep::Last` it takes the later line, since lines with equal keys may differ
+    /// elsewhere. `sources` is left to the caller, which knows whether the inputs differ.
+    pub fn absorb(&mut self, later: Line, keep: Keep) {
+        self.count += later.count;
+        if keep == Keep::Last {
+            self.key = later.key;
+            self.text = later.text;
+            self.number = later.number;
+        }
+    }
+}
+
+/// Order in which the lines of a temporary file are sorted
+#[derive(Clone, Copy)]
+pub enum SortOrder {
+    /// Byte-wise by key, ties broken by line number
+    Key,
+    /// By original line number
+    Position,
+}
+
+impl SortOrder {
+    pub fn compare(self, a: &Line, b: &Line) -> Ordering {
+        match self {
+            SortOrder::Key => a.key().cmp(b.key()).then(a.number.cmp(&b.number)),
+            SortOrder::Position => a.number.cmp(&b.number),
+        }
+    }
+}
+
+/// Identifies a temporary run file
+const MAGIC: &[u8; 8] = b"DEDUPRUN";
+/// Version of the run format, changed whenever the layout of entries changes
+const VERSION: u32 = 1;
+/// Size of the header: magic, version, entry count and checksum
+const HEADER_LEN: usize = 24;
+
+/// Writes lines to a new temporary file in a binary format independent of the input's
+///
+/// The file starts with an uncompressed header holding the magic bytes, the format version,
+/// the number of entries and a CRC-32 of the uncompressed entries, all little-endian.
+/// The entries follow, compressed with `compression`. Each entry holds the line number,
+/// count, source, sources, key shared length, key suffix length plus one (zero when the
+/// whole line is the key), text shared length and text suffix length as LEB128 varints,
+/// followed by the key and text suffixes. Keys and texts are front-coded: the shared
+/// lengths give the number of leading bytes taken from the previous entry.
+pub fn write_run(lines: &[Line], temp_dir: &Path, compression: Compression) -> io::Result<TempPath> {
+    let mut writer = RunWriter::new(temp_dir, compression)?;
+    for line in lines {
+        writer.write_line(line)?;
+    }
+    writer.finish()
+}
+
+/// Writes the entries of a temporary file one at a time, in the format described by `write_run`
+pub struct RunWriter {
+    /// Path of the file, which is deleted when dropped; the file itself is only held open
+    /// while writing, so that many runs can wait to be merged without using up descriptors
+    path: TempPath,
+    writer: BufWriter<Encoder<File>>,
+    entries: u64,
+    checksum: crc32fast::Hasher,
+    /// Encoded entry, reused between entries
+    entry: Vec<u8>,
+    /// Key and text of the previous entry, which the next entry's prefixes are shared with
+    previous_key: Vec<u8>,
+    previous_text: Vec<u8>,
+}
+
+impl RunWriter {
+    pub fn new(temp_dir: &Path, compression: Compression) -> io::Result<Self> {
+        let (mut file, path) = NamedTempFile::new_in(temp_dir)?.into_parts();
+        // Reserve room for the header, which is filled in by `finish`
+        file.write_all(&[0; HEADER_LEN])?;
+        let writer = BufWriter::new(compression.encoder(file)?);
+        Ok(RunWriter {
+            path,
+            writer,
+            entries: 0,
+            checksum: crc32fast::Hasher::new(),
+            entry: Vec::new(),
+            previous_key: Vec::new(),
+            previous_text: Vec::new(),
+        })
+    }
+
+    pub fn write_line(&mut self, line: &Line) -> io::Result<()> {
+        let text_shared = shared_prefix_len(&self.previous_text, &line.text);
+        let (key_shared, key_suffix) = match &line.key {
+            Some(key) => {
+                let key_shared = shared_prefix_len(&self.previous_key, key);
+                (key_shared, Some(&key[key_shared..]))
+            }
+            None => (0, None),
+        };
+        let text_suffix = &line.text[text_shared..];
+
+        let entry = &mut self.entry;
+        entry.clear();
+        for value in [
+            line.number,
+            line.count,
+            line.source.into(),
+            line.sources.into(),
+            key_shared as u64,
+            key_suffix.map_or(0, |suffix| suffix.len() as u64 + 1),
+            text_shared as u64,
+            text_suffix.len() as u64,
+        ] {
+            write_varint(entry, value);
+        }
+        entry.extend_from_slice(key_suffix.unwrap_or_default());
+        entry.extend_from_slice(text_suffix);
+        self.checksum.update(entry);
+        self.writer.write_all(entry)?;
+        self.entries += 1;
+
+        if let Some(key) = &line.key {
+            self.previous_key.clear();
+            self.previous_key.extend_from_slice(key);
+        }
+        self.previous_text.clear();
+        self.previous_text.extend_from_slice(&line.text);
+        Ok(())
+    }
+
+    /// Flushes the remaining entries, writes the header and returns the finished temporary file
+    pub fn finish(self) -> io::Result<TempPath> {
+        let mut file = self.writer.into_inner().map_err(|e| e.into_error())?.finish()?;
+        let mut header = Vec::with_capacity(HEADER_LEN);
+        header.extend_from_slice(MAGIC);
+        header.extend_from_slice(&VERSION.to_le_bytes());
+        header.extend_from_slice(&self.entries.to_le_bytes());
+        header.extend_from_slice(&self.checksum.finalize().to_le_bytes());
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&header)?;
+        Ok(self.path)
+    }
+}
+
+/// Appends `value` as an unsigned LEB128 varint
+fn write_varint(buffer: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buffer.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.push(value as u8);
+}
+
+/// Length in bytes of the longest common prefix of `a` and `b`
+fn shared_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
+
+/// Reads back the entries of a temporary file written by `write_run`
+struct RunReader {
+    reader: Box<dyn BufRead>,
+    /// Entries left to read, and the checksum they must add up to
+    remaining: u64,
+    expected_checksum: u32,
+    checksum: crc32fast::Hasher,
+    /// Key and text of the previous entry, which the next entry's prefixes are taken from
+    previous_key: Vec<u8>,
+    previous_text: Vec<u8>,
+}
+
+impl RunReader {
+    fn open(path: &Path, compression: Compression) -> io::Result<Self> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut header = [0; HEADER_LEN];
+        file.read_exact(&mut header)?;
+        let (magic, rest) = header.split_at(MAGIC.len());
+        let (version, rest) = rest.split_at(4);
+        let (entries, checksum) = rest.split_at(8);
+        if magic != MAGIC {
+            return Err(corrupt("not a temporary run file"));
+        }
+        let version = u32::from_le_bytes(version.try_into().expect("4 bytes"));
+        if version != VERSION {
+            return Err(corrupt(&format!("unsupported temporary file version {}", version)));
+        }
+        Ok(RunReader {
+            reader: compression.decoder(Box::new(file))?,
+            remaining: u64::from_le_bytes(entries.try_into().expect("8 bytes")),
+            expected_checksum: u32::from_le_bytes(checksum.try_into().expect("4 bytes")),
+            checksum: crc32fast::Hasher::new(),
+            previous_key: Vec::new(),
+            previous_text: Vec::new(),
+        })
+    }
+
+    /// Reads the next entry, returning `None` after the last one
+    fn next_line(&mut self) -> io::Result<Option<Line>> {
+        if self.remaining == 0 {
+            // Verify the whole file once every entry has been read
+            if !self.reader.fill_buf()?.is_empty() {
+                return Err(corrupt("unexpected data after the last entry"));
+            }
+            if self.checksum.clone().finalize() != self.expected_checksum {
+                return Err(corrupt("checksum mismatch"));
+            }
+            return Ok(None);
+        }
+        self.remaining -= 1;
+
+        let number = self.read_varint()?;
+        let count = self.read_varint()?;
+        let source = self.read_varint()?.try_into().map_err(|_| corrupt("source out of range"))?;
+        let sources = self.read_varint()?.try_into().map_err(|_| corrupt("sources out of range"))?;
+        let key_shared = self.read_varint()? as usize;
+        let key_len = self.read_varint()? as usize;
+        let text_shared = self.read_varint()? as usize;
+        let text_len = self.read_varint()? as usize;
+
+        let key = match key_len.checked_sub(1) {
+            Some(key_len) => {
+                if key_shared > self.previous_key.len() {
+                    return Err(corrupt("key prefix out of range"));
+                }
+                self.previous_key.truncate(key_shared);
+                let suffix = self.read_bytes(key_len)?;
+                self.previous_key.extend_from_slice(&suffix);
+                Some(self.previous_key.clone())
+            }
+            None => None,
+        };
+        if text_shared > self.previous_text.len() {
+            return Err(corrupt("text prefix out of range"));
+        }
+        self.previous_text.truncate(text_shared);
+        let suffix = self.read_bytes(text_len)?;
+        self.previous_text.extend_from_slice(&suffix);
+        Ok(Some(Line { key, text: self.previous_text.clone(), number, count, source, sources }))
+    }
+
+    /// Reads an unsigned LEB128 varint
+    fn read_varint(&mut self) -> io::Result<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let [byte] = self.read_array::<1>()?;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(corrupt("varint too long"))
+    }
+
+    fn read_array<const N: usize>(&mut self) -> io::Result<[u8; N]> {
+        let mut bytes = [0; N];
+        self.reader.read_exact(&mut bytes).map_err(truncated)?;
+        self.checksum.update(&bytes);
+        Ok(bytes)
+    }
+
+    fn read_bytes(&mut self, len: usize) -> io::Result<Vec<u8>> {
+        let mut bytes = Vec::new();
+        // Read through `take` so that a corrupt length cannot allocate more than the file holds
+        (&mut self.reader).take(len as u64).read_to_end(&mut bytes)?;
+        if bytes.len() != len {
+            return Err(corrupt("truncated entry"));
+        }
+        self.checksum.update(&bytes);
+        Ok(bytes)
+    }
+}
+
+/// Error for a temporary file that does not hold what was written to it
+fn corrupt(reason: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("corrupt temporary file: {}", reason))
+}
+
+/// Reports an entry cut short by the end of the file as corruption
+fn truncated(error: io::Error) -> io::Error {
+    match error.kind() {
+        io::ErrorKind::UnexpectedEof => corrupt("truncated entry"),
+        _ => error,
+    }
+}
+
+/// An entry in the merge heap: a line and the index of the reader it came from
+struct HeapEntry {
+    line: Line,
+    index: usize,
+    order: SortOrder,
+}
+
+impl PartialEq for HeapEntry {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for HeapEntry {}
+
+impl PartialOrd for HeapEntry {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for HeapEntry {
+    // Reversed because Rust's `BinaryHeap` is a max-heap by default
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.order.compare(&other.line, &self.line)
+    }
+}
+
+/// Yields the lines of several sorted temporary files in merged order
+pub struct RunMerger {
+    readers: Vec<RunReader>,
+    heap: BinaryHeap<HeapEntry>,
+    order: SortOrder,
+}
+
+impl RunMerger {
+    pub fn new(temp_files: Vec<TempPath>, order: SortOrder, compression: Compression) -> io::Result<Self> {
+        // Create a vector of readers, one for each temporary file
+        // These readers will allow reading lines from each file one at a time
+        let mut readers = temp_files
+            .iter()
+            .map(|path| RunReader::open(path, compression))
+            .collect::<io::Result<Vec<_>>>()?;
+
+        // Initialize the heap with the first line from each reader
+        let mut heap = BinaryHeap::new();
+        for (index, reader) in readers.iter_mut().enumerate() {
+            if let Some(line) = reader.next_line()? {
+                heap.push(HeapEntry { line, index, order });
+            }
+        }
+        Ok(RunMerger { readers, heap, order })
+    }
+
+    /// Returns the smallest remaining line, refilling the heap from the reader it came from
+    pub fn next_line(&mut self) -> io::Result<Option<Line>> {
+        let Some(HeapEntry { line, index, .. }) = self.heap.pop() else {
+            return Ok(None);
+        };
+        if let Some(next) = self.readers[index].next_line()? {
+            self.heap.push(HeapEntry { line: next, index, order: self.order });
+        }
+        Ok(Some(line))
+    }
+
+    /// Returns the surviving occurrence of the next distinct key, consuming all of its duplicates,
+    /// summing their counts and counting the distinct inputs they came from
+    ///
+    /// Only meaningful for `SortOrder::Key`, where duplicates arrive consecutively in
+    /// ascending line number order, and therefore grouped by input.
+    pub fn next_distinct(&mut self, keep: Keep) -> io::Result<Option<Line>> {
+        let Some(mut line) = self.next_line()? else {
+            return Ok(None);
+        };
+        let mut last_source = line.source;
+        let mut sources = line.sources;
+        while self.heap.peek().is_some_and(|top| top.line.key() == line.key()) {
+            let duplicate = self.next_line()?.expect("heap entry was just peeked");
+            if duplicate.source != last_source {
+                sources += 1;
+                last_source = duplicate.source;
+            }
+            line.absorb(duplicate, keep);
+        }
+        line.sources = sources;
+        Ok(Some(line))
+    }
+
+    /// Returns the next line combined with the following duplicates that an intermediate
+    /// merge may fold into it, summing their counts
+    ///
+    /// With `per_source`, only duplicates from the same input are combined, as when
+    /// deduplicating a chunk, so that the final merge can still count distinct inputs.
+    pub fn next_combined(&mut self, keep: Keep, per_source: bool) -> io::Result<Option<Line>> {
+        let Some(mut line) = self.next_line()? else {
+            return Ok(None);
+        };
+        while self
+            .heap
+            .peek()
+            .is_some_and(|top| top.line.key() == line.key() && (!per_source || top.line.source == line.source))
+        {
+            let duplicate = self.next_line()?.expect("heap entry was just peeked");
+            line.absorb(duplicate, keep);
+        }
+        Ok(Some(line))
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Fields = (Option<Vec<u8>>, Vec<u8>, u64, u64, u32, u32);
+
+    fn line(key: Option<&[u8]>, text: &[u8], number: u64) -> Line {
+        Line { key: key.map(<[u8]>::to_vec), text: text.to_vec(), number, count: number + 1, source: 2, sources: 3 }
+    }
+
+    fn fields(line: &Line) -> Fields {
+        (line.key.clone(), line.text.clone(), line.number, line.count, line.source, line.sources)
+    }
+
+    fn sample() -> Vec<Line> {
+        vec![
+            line(None, b"", 0),
+            line(None, b"", 1),
+            line(Some(b""), b"", 2),
+            line(Some(b"key"), b"a\nb", 3),
+            line(Some(b"key\0"), b"a\nb\0c", 4),
+            line(None, b"a\0", 5),
+            line(Some(b"k"), b"\n", u64::MAX - 1),
+            line(None, &[0xff; 300], 300),
+        ]
+    }
+
+    fn read_all(path: &Path, compression: Compression) -> io::Result<Vec<Fields>> {
+        let mut reader = RunReader::open(path, compression)?;
+        let mut lines = Vec::new();
+        while let Some(line) = reader.next_line()? {
+            lines.push(fields(&line));
+        }
+        Ok(lines)
+    }
+
+    /// Writes the sample to an uncompressed run, edits its bytes, and reads it back
+    fn read_edited(edit: impl FnOnce(&mut Vec<u8>)) -> io::Error {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_run(&sample(), dir.path(), Compression::None).unwrap();
+        let mut bytes = std::fs::read(&path).unwrap();
+        edit(&mut bytes);
+        std::fs::write(&path, bytes).unwrap();
+        let error = read_all(&path, Compression::None).unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::InvalidData, "{}", error);
+        error
+    }
+
+    #[test]
+    fn absorb_keeps_first_input() {
+        let mut first = Line { source: 0, sources: 1, ..line(Some(b"k"), b"first", 1) };
+        first.absorb(Line { source: 1, ..line(Some(b"k"), b"second", 4) }, Keep::First);
+        assert_eq!(fields(&first), (Some(b"k".to_vec()), b"first".to_vec(), 1, 7, 0, 1));
+
+        let mut last = Line { source: 0, sources: 1, ..line(Some(b"k"), b"first", 1) };
+        last.absorb(Line { source: 1, ..line(Some(b"k"), b"second", 4) }, Keep::Last);
+        assert_eq!(fields(&last), (Some(b"k".to_vec()), b"second".to_vec(), 4, 7, 0, 1));
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let expected = sample().iter().map(fields).collect::<Vec<_>>();
+        for compression in [Compression::None, Compression::Lz4, Compression::Zstd] {
+            let path = write_run(&sample(), dir.path(), compression).unwrap();
+            assert_eq!(read_all(&path, compression).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn round_trip_empty_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_run(&[], dir.path(), Compression::None).unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), HEADER_LEN as u64);
+        assert_eq!(read_all(&path, Compression::None).unwrap(), Vec::new());
+    }
+
+    #[test]
+    fn rejects_flipped_byte() {
+        let error = read_edited(|bytes| *bytes.last_mut().unwrap() ^= 1);
+        assert!(error.to_string().contains("checksum mismatch"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_truncated_file() {
+        let error = read_edited(|bytes| bytes.truncate(bytes.len() - 1));
+        assert!(error.to_string().contains("truncated entry"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_trailing_bytes() {
+        let error = read_edited(|bytes| bytes.push(0));
+        assert!(error.to_string().contains("unexpected data after the last entry"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_wrong_magic() {
+        let error = read_edited(|bytes| bytes[0] = b'X');
+        assert!(error.to_string().contains("not a temporary run file"), "{}", error);
+    }
+
+    #[test]
+    fn rejects_wrong_version() {
+        let error = read_edited(|bytes| bytes[MAGIC.len()..MAGIC.len() + 4].copy_from_slice(&2u32.to_le_bytes()));
+        assert!(error.to_string().contains("unsupported temporary file version 2"), "{}", error);
+    }
+}
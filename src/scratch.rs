@@ -0,0 +1,56 @@
+use indicatif::HumanBytes;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use tempfile::TempDir;
+
+/// Temporary directories that runs are striped across
+///
+/// Each directory is removed, with the runs in it, when this is dropped.
+pub struct ScratchDirs {
+    dirs: Vec<TempDir>,
+    next: AtomicUsize,
+}
+
+impl ScratchDirs {
+    /// Creates a temporary directory inside each of `parents`, or in the system default
+    /// location when none are given
+    pub fn new(parents: &[PathBuf]) -> io::Result<Self> {
+        let dirs = if parents.is_empty() {
+            vec![tempfile::tempdir()?]
+        } else {
+            parents.iter().map(tempfile::tempdir_in).collect::<io::Result<Vec<_>>>()?
+        };
+        Ok(ScratchDirs { dirs, next: AtomicUsize::new(0) })
+    }
+
+    /// Returns the directory the next run should be written to, cycling through all of them
+    pub fn next_dir(&self) -> &Path {
+        let index = self.next.fetch_add(1, Ordering::Relaxed) % self.dirs.len();
+        self.dirs[index].path()
+    }
+
+    /// Fails if any directory lacks the free space for its share of `required` bytes
+    ///
+    /// Runs are spread evenly, so each directory needs an equal share. Directories on the
+    /// same filesystem are each checked against its full free space.
+    pub fn check_space(&self, required: u64) -> io::Result<()> {
+        let share = required / self.dirs.len() as u64;
+        for dir in &self.dirs {
+            let available = fs4::available_space(dir.path())?;
+            if available < share {
+                return Err(io::Error::new(
+                    io::ErrorKind::StorageFull,
+                    format!(
+                        "not enough space for temporary files in {}: about {} needed, {} available; \
+                         use --temp-dir to choose other or more locations, or --temp-compression to need less",
+                        dir.path().parent().unwrap_or(dir.path()).display(),
+                        HumanBytes(share),
+                        HumanBytes(available),
+                    ),
+                ));
+            }
+        }
+        Ok(())
+    }
+}